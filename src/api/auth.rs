@@ -0,0 +1,215 @@
+use ring::constant_time::verify_slices_are_equal;
+use std::net::IpAddr;
+use tiny_http::Request;
+
+/// Access level granted to an API caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    ReadOnly,
+    Admin,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::ReadOnly),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role {}, expected read or admin", s)),
+        }
+    }
+}
+
+/// Why a request was turned away.
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+    /// No or wrong credentials, answered with 401
+    Unauthenticated,
+    /// The caller is not allowed to do this, answered with 403
+    Forbidden(String),
+}
+
+/// Credentials and client restrictions of the API server.
+///
+/// With no credentials configured every caller is an admin, as before authentication existed.
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Expected `Authorization` header values and the role they grant
+    credentials: Vec<(String, Role)>,
+    /// If not empty, only these client addresses are served
+    allowed_ips: Vec<IpAddr>,
+}
+
+impl Config {
+    pub fn add_token(&mut self, token: &str, role: Role) {
+        self.credentials.push((format!("Bearer {}", token), role));
+    }
+
+    pub fn add_basic(&mut self, user: &str, password: &str, role: Role) {
+        let encoded = base64_encode(format!("{}:{}", user, password).as_bytes());
+        self.credentials.push((format!("Basic {}", encoded), role));
+    }
+
+    pub fn allow_ip(&mut self, ip: IpAddr) {
+        self.allowed_ips.push(ip);
+    }
+
+    pub fn has_credentials(&self) -> bool {
+        !self.credentials.is_empty()
+    }
+
+    /// Load credentials from a file with one `<role> <credential>` pair per line, e.g.
+    ///
+    /// ```text
+    /// # role  credential
+    /// admin   token:s3cret
+    /// read    basic:alice:password
+    /// ```
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        for (num, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (role, credential) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(r), Some(c), None) => (r, c),
+                _ => return Err(format!("line {}: expected <role> <credential>", num + 1)),
+            };
+            let role = role
+                .parse::<Role>()
+                .map_err(|e| format!("line {}: {}", num + 1, e))?;
+            let mut parts = credential.splitn(3, ':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("token"), Some(token), None) if !token.is_empty() => {
+                    self.add_token(token, role)
+                }
+                (Some("basic"), Some(user), Some(password)) => self.add_basic(user, password, role),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected token:<token> or basic:<user>:<password>",
+                        num + 1
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Determine the role of the caller of a request
+    pub fn authorize(&self, req: &Request) -> Result<Role, Denied> {
+        let header = req
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.as_str());
+        self.role_of(req.remote_addr().ip(), header)
+    }
+
+    /// Determine the role of a caller from its address and `Authorization` header
+    fn role_of(&self, ip: IpAddr, header: Option<&str>) -> Result<Role, Denied> {
+        if !self.allowed_ips.is_empty() && !self.allowed_ips.contains(&ip) {
+            return Err(Denied::Forbidden(format!("{} is not allowed", ip)));
+        }
+        if self.credentials.is_empty() {
+            return Ok(Role::Admin);
+        }
+        let header = header.ok_or(Denied::Unauthenticated)?;
+        // check every credential so the time taken doesn't reveal which one matched
+        let mut granted = None;
+        for (expected, role) in &self.credentials {
+            if verify_slices_are_equal(expected.as_bytes(), header.as_bytes()).is_ok() {
+                granted = Some(*role);
+            }
+        }
+        granted.ok_or(Denied::Unauthenticated)
+    }
+}
+
+fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"alice:password"), "YWxpY2U6cGFzc3dvcmQ=");
+    }
+
+    fn load(content: &str) -> Result<Config, String> {
+        let path = std::env::temp_dir().join(format!("auth-test-{}", rand::random::<u64>()));
+        std::fs::write(&path, content).unwrap();
+        let mut config = Config::default();
+        let result = config.load_file(&path.to_string_lossy());
+        std::fs::remove_file(&path).unwrap();
+        result.map(|()| config)
+    }
+
+    #[test]
+    fn load_credentials() {
+        let config = load("# role  credential\n\nadmin   token:s3cret\n  read basic:alice:pass:word  \n").unwrap();
+        assert_eq!(
+            config.credentials,
+            vec![
+                ("Bearer s3cret".to_string(), Role::Admin),
+                (format!("Basic {}", base64_encode(b"alice:pass:word")), Role::ReadOnly),
+            ]
+        );
+        assert!(load("# only comments\n\n").unwrap().credentials.is_empty());
+
+        assert!(load("owner token:s3cret").err().unwrap().contains("unknown role owner"));
+        for malformed in &["admin", "admin token:a token:b", "admin token:", "admin basic:alice", "admin s3cret"] {
+            assert!(load(malformed).err().unwrap().starts_with("line 1:"), "{}", malformed);
+        }
+        assert!(load("admin token:a\nread token").err().unwrap().starts_with("line 2:"));
+    }
+
+    #[test]
+    fn roles() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // without credentials everyone is an admin
+        let mut config = Config::default();
+        assert_eq!(config.role_of(remote, None), Ok(Role::Admin));
+        assert_eq!(config.role_of(remote, Some("Bearer guess")), Ok(Role::Admin));
+
+        config.add_token("s3cret", Role::Admin);
+        config.add_basic("alice", "password", Role::ReadOnly);
+        assert_eq!(config.role_of(remote, Some("Bearer s3cret")), Ok(Role::Admin));
+        assert_eq!(config.role_of(remote, Some("Basic YWxpY2U6cGFzc3dvcmQ=")), Ok(Role::ReadOnly));
+        assert_eq!(config.role_of(remote, Some("Bearer guess")), Err(Denied::Unauthenticated));
+        assert_eq!(config.role_of(remote, None), Err(Denied::Unauthenticated));
+
+        // the allowlist applies before credentials are looked at
+        config.allow_ip(local);
+        assert_eq!(config.role_of(local, Some("Bearer s3cret")), Ok(Role::Admin));
+        assert!(matches!(config.role_of(remote, Some("Bearer s3cret")), Err(Denied::Forbidden(_))));
+        let mut open = Config::default();
+        open.allow_ip(local);
+        assert!(matches!(open.role_of(remote, None), Err(Denied::Forbidden(_))));
+    }
+}
@@ -1,11 +1,26 @@
+pub mod auth;
+pub mod rpc;
+
 use serde::Serialize;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H160;
+use crate::events::{EventBus, Record};
+use auth::{Denied, Role};
+use crate::mempool::Mempool;
 use crate::miner::Handle as MinerHandle;
-use crate::network::server::Handle as NetworkServerHandle;
+use crate::miner::MinerState;
+use crate::network::server::{Handle as NetworkServerHandle, MAX_BAN_DURATION};
 use crate::network::message::Message;
+use crate::shutdown::Handle as ShutdownHandle;
+use crate::txgen::{Handle as TxGenHandle, MIN_RATE};
 
-use log::info;
+use log::{info, warn};
 use std::collections::HashMap;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 use tiny_http::Header;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
@@ -14,7 +29,13 @@ use url::Url;
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
+    txgen: TxGenHandle,
     network: NetworkServerHandle,
+    blkchain: Arc<RwLock<Blockchain>>,
+    mem_pool: Arc<Mutex<Mempool>>,
+    events: EventBus,
+    auth: Arc<auth::Config>,
+    shutdown: ShutdownHandle,
 }
 
 #[derive(Serialize)]
@@ -23,6 +44,16 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct EventsResponse {
+    /// Sequence number to pass as `since` in the next poll
+    next: u64,
+    events: Vec<Record>,
+}
+
+/// Longest a client may wait for new events in one poll, in seconds
+const MAX_POLL_TIMEOUT: u64 = 60;
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -36,22 +67,66 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_status {
+    ( $req:expr, $status:expr, $message:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let payload = ApiResponse {
+            success: false,
+            message: $message.to_string(),
+        };
+        let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+            .with_header(content_type)
+            .with_status_code($status);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
+        txgen: &TxGenHandle,
         network: &NetworkServerHandle,
+        blkchain: &Arc<RwLock<Blockchain>>,
+        mem_pool: &Arc<Mutex<Mempool>>,
+        events: &EventBus,
+        auth: auth::Config,
+        shutdown: &ShutdownHandle,
     ) {
+        if !auth.has_credentials() && !addr.ip().is_loopback() {
+            warn!("API server at {} is reachable from other hosts without authentication", &addr);
+        }
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
+            txgen: txgen.clone(),
             network: network.clone(),
+            blkchain: Arc::clone(blkchain),
+            mem_pool: Arc::clone(mem_pool),
+            events: events.clone(),
+            auth: Arc::new(auth),
+            shutdown: shutdown.clone(),
         };
         thread::spawn(move || {
-            for req in server.handle.incoming_requests() {
+            for mut req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
+                let txgen = server.txgen.clone();
                 let network = server.network.clone();
+                let blkchain = Arc::clone(&server.blkchain);
+                let mem_pool = Arc::clone(&server.mem_pool);
+                let events = server.events.clone();
+                let auth = Arc::clone(&server.auth);
+                let shutdown = server.shutdown.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -62,7 +137,60 @@ impl Server {
                             return;
                         }
                     };
+                    let role = match auth.authorize(&req) {
+                        Ok(role) => role,
+                        Err(Denied::Unauthenticated) => {
+                            let challenge = "WWW-Authenticate: Basic realm=\"bitcoin\""
+                                .parse::<Header>()
+                                .unwrap();
+                            let payload = ApiResponse {
+                                success: false,
+                                message: "authentication required".to_string(),
+                            };
+                            let resp = Response::from_string(
+                                serde_json::to_string_pretty(&payload).unwrap(),
+                            )
+                            .with_header(challenge)
+                            .with_status_code(401);
+                            req.respond(resp).unwrap();
+                            return;
+                        }
+                        Err(Denied::Forbidden(message)) => {
+                            respond_status!(req, 403, message);
+                            return;
+                        }
+                    };
+                    if role < required_role(url.path()) {
+                        respond_status!(req, 403, "admin access required");
+                        return;
+                    }
                     match url.path() {
+                        "/rpc" => {
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let ctx = rpc::Context {
+                                blkchain,
+                                mem_pool,
+                                miner,
+                                network,
+                                role,
+                            };
+                            match rpc::handle(&body, &ctx) {
+                                Some(payload) => {
+                                    let content_type =
+                                        "Content-Type: application/json".parse::<Header>().unwrap();
+                                    let resp =
+                                        Response::from_string(payload).with_header(content_type);
+                                    req.respond(resp).unwrap();
+                                }
+                                None => {
+                                    req.respond(Response::empty(204)).unwrap();
+                                }
+                            }
+                        }
                         "/miner/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -84,26 +212,274 @@ impl Server {
                                     return;
                                 }
                             };
-                            miner.start(lambda);
+                            if miner.status().state == MinerState::Shutdown {
+                                respond_result!(req, false, "miner has been stopped");
+                                return;
+                            }
+                            // the miner may still exit between the check above and now
+                            match miner.start(lambda) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/pause" => {
+                            if miner.status().state == MinerState::Shutdown {
+                                respond_result!(req, false, "miner has been stopped");
+                                return;
+                            }
+                            match miner.pause() {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/stop" => {
+                            if miner.status().state == MinerState::Shutdown {
+                                respond_result!(req, false, "miner has been stopped");
+                                return;
+                            }
+                            miner.exit();
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/generate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let n = match params.get("n").map(|v| v.parse::<usize>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing n: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing n");
+                                    return;
+                                }
+                            };
+                            let address = match params.get("address").map(|v| v.parse::<H160>()) {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing address: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            match miner.generate(n, address) {
+                                Ok(hashes) => {
+                                    let hashes: Vec<String> =
+                                        hashes.iter().map(|h| h.to_string()).collect();
+                                    respond_json!(req, hashes);
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                }
+                            }
+                        }
+                        "/miner/status" => {
+                            respond_json!(req, miner.status());
+                        }
+                        "/txgen/start" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let rate = match params.get("rate").map(|v| v.parse::<f64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing rate: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing rate");
+                                    return;
+                                }
+                            };
+                            if !(rate > 0.0 && rate.is_finite()) {
+                                respond_result!(req, false, "rate must be a positive number");
+                                return;
+                            }
+                            // slower rates would wait longer than a `Duration` can hold
+                            txgen.start(rate.max(MIN_RATE));
+                            respond_result!(req, true, "ok");
+                        }
+                        "/txgen/stop" => {
+                            txgen.stop();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/events" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let since = match params.get("since").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing since: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => events.next_seq(),
+                            };
+                            let timeout = match params.get("timeout").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v.min(MAX_POLL_TIMEOUT),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing timeout: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => MAX_POLL_TIMEOUT / 2,
+                            };
+                            let records = events.poll(since, Duration::from_secs(timeout));
+                            let next = records.last().map(|r| r.seq + 1).unwrap_or(since);
+                            respond_json!(req, EventsResponse { next, events: records });
+                        }
+                        "/shutdown" => {
+                            if shutdown.request("requested through the API") {
+                                respond_result!(req, true, "shutting down");
+                            } else {
+                                respond_result!(req, false, "already shutting down");
+                            }
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
-                        _ => {
-                            let content_type =
-                                "Content-Type: application/json".parse::<Header>().unwrap();
-                            let payload = ApiResponse {
-                                success: false,
-                                message: "endpoint not found".to_string(),
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
+                        }
+                        "/network/queue" => {
+                            respond_json!(req, network.queue_depth());
+                        }
+                        "/network/connect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
                             };
-                            let resp = Response::from_string(
-                                serde_json::to_string_pretty(&payload).unwrap(),
-                            )
-                            .with_header(content_type)
-                            .with_status_code(404);
-                            req.respond(resp).unwrap();
+                            let addr = match addr.parse::<SocketAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing addr: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            match network.connect(addr) {
+                                Ok(_) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(
+                                    req,
+                                    false,
+                                    format!("error connecting to {}: {}", addr, e)
+                                ),
+                            }
+                        }
+                        "/network/disconnect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // peers are given by id, or by address to drop every connection from it
+                            let ids: Vec<usize> = match (params.get("id"), params.get("addr")) {
+                                (Some(id), _) => match id.parse::<usize>() {
+                                    Ok(v) => vec![v],
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing id: {}", e));
+                                        return;
+                                    }
+                                },
+                                (None, Some(addr)) => match addr.parse::<SocketAddr>() {
+                                    Ok(addr) => network
+                                        .peers()
+                                        .iter()
+                                        .filter(|peer| peer.addr == addr)
+                                        .map(|peer| peer.id)
+                                        .collect(),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing addr: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                (None, None) => {
+                                    respond_result!(req, false, "missing id or addr");
+                                    return;
+                                }
+                            };
+                            let disconnected = ids.into_iter().filter(|id| network.disconnect(*id)).count();
+                            if disconnected > 0 {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "peer is not connected");
+                            }
+                        }
+                        "/network/ban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match params.get("ip") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing ip");
+                                    return;
+                                }
+                            };
+                            let ip = match ip.parse::<IpAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing ip: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let duration = match params.get("duration") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing duration");
+                                    return;
+                                }
+                            };
+                            let duration = match duration.parse::<u64>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing duration: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let duration = Duration::from_secs(duration);
+                            if duration > MAX_BAN_DURATION {
+                                respond_result!(
+                                    req,
+                                    false,
+                                    format!(
+                                        "duration must be at most {} seconds",
+                                        MAX_BAN_DURATION.as_secs()
+                                    )
+                                );
+                                return;
+                            }
+                            network.ban(ip, duration);
+                            respond_result!(req, true, "ok");
+                        }
+                        _ => {
+                            respond_status!(req, 404, "endpoint not found");
                         }
                     }
                 });
@@ -112,3 +488,11 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+/// Least role needed to call an endpoint. Calls to `/rpc` are further checked per method.
+fn required_role(path: &str) -> Role {
+    match path {
+        "/rpc" | "/miner/status" | "/events" | "/network/peers" | "/network/queue" => Role::ReadOnly,
+        _ => Role::Admin,
+    }
+}
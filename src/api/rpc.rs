@@ -0,0 +1,437 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use super::auth::Role;
+use crate::blockchain::Blockchain;
+use crate::block::Block;
+use crate::crypto::hash::{Hashable, H160, H256};
+use crate::mempool::Mempool;
+use crate::locks;
+use crate::miner::Handle as MinerHandle;
+use crate::network::message::Message;
+use crate::network::server::Handle as NetworkServerHandle;
+use crate::transaction::{verify, SignedTrans};
+
+use std::sync::{Arc, Mutex, RwLock};
+
+// standard JSON-RPC 2.0 error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+// application errors, in the range reserved for implementations
+pub const NOT_FOUND: i64 = -32001;
+pub const TRANSACTION_REJECTED: i64 = -32002;
+pub const UNAUTHORIZED: i64 = -32003;
+pub const MINING_FAILED: i64 = -32004;
+
+/// The node handles an RPC call may touch.
+pub struct Context {
+    pub blkchain: Arc<RwLock<Blockchain>>,
+    pub mem_pool: Arc<Mutex<Mempool>>,
+    pub miner: MinerHandle,
+    pub network: NetworkServerHandle,
+    /// Role of the caller, which decides the methods it may use
+    pub role: Role,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Error {
+    pub code: i64,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(code: i64, message: &str) -> Self {
+        Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Error>,
+    id: Value,
+}
+
+impl Response {
+    fn new(id: Value, outcome: Result<Value, Error>) -> Self {
+        let (result, error) = match outcome {
+            Ok(v) => (Some(v), None),
+            Err(e) => (None, Some(e)),
+        };
+        Response {
+            jsonrpc: "2.0",
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct InputInfo {
+    pub previous_hash: String,
+    pub val: u8,
+}
+
+#[derive(Serialize)]
+pub struct OutputInfo {
+    pub address: String,
+    pub val: u8,
+}
+
+#[derive(Serialize)]
+pub struct TransactionInfo {
+    pub hash: String,
+    pub inputs: Vec<InputInfo>,
+    pub outputs: Vec<OutputInfo>,
+}
+
+impl From<&SignedTrans> for TransactionInfo {
+    fn from(signed: &SignedTrans) -> Self {
+        TransactionInfo {
+            hash: signed.hash().to_string(),
+            inputs: signed
+                .tx
+                .tx_in
+                .iter()
+                .map(|i| InputInfo {
+                    previous_hash: i.previous_hash.to_string(),
+                    val: i.val,
+                })
+                .collect(),
+            outputs: signed
+                .tx
+                .tx_out
+                .iter()
+                .map(|o| OutputInfo {
+                    address: o.address.to_string(),
+                    val: o.val,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BlockInfo {
+    pub hash: String,
+    pub parent: String,
+    pub index: usize,
+    pub nonce: u32,
+    pub difficulty: String,
+    pub merkle_root: String,
+    pub timestamp: u64,
+    pub transactions: Vec<TransactionInfo>,
+}
+
+impl From<&Block> for BlockInfo {
+    fn from(block: &Block) -> Self {
+        BlockInfo {
+            hash: block.hash().to_string(),
+            parent: block.head.block_parent.to_string(),
+            index: block.index,
+            nonce: block.head.nonce,
+            difficulty: block.head.difficulty.to_string(),
+            merkle_root: block.head.mkl_root.to_string(),
+            timestamp: block.head.time_stamp,
+            transactions: block.body.data.iter().map(TransactionInfo::from).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BalanceInfo {
+    pub address: String,
+    pub balance: u64,
+    pub outputs: usize,
+}
+
+/// Handle the body of a `/rpc` request, which is either a single call or a batch.
+/// Returns `None` when nothing should be sent back, i.e. only notifications were received.
+pub fn handle(body: &str, ctx: &Context) -> Option<String> {
+    let request: Value = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => {
+            let resp = Response::new(Value::Null, Err(Error::new(PARSE_ERROR, &e.to_string())));
+            return Some(serde_json::to_string(&resp).unwrap());
+        }
+    };
+    match request {
+        Value::Array(calls) => {
+            if calls.is_empty() {
+                let resp = Response::new(Value::Null, Err(Error::new(INVALID_REQUEST, "empty batch")));
+                return Some(serde_json::to_string(&resp).unwrap());
+            }
+            let responses: Vec<Response> = calls.iter().filter_map(|c| handle_call(c, ctx)).collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(serde_json::to_string(&responses).unwrap())
+            }
+        }
+        call => handle_call(&call, ctx).map(|r| serde_json::to_string(&r).unwrap()),
+    }
+}
+
+fn handle_call(call: &Value, ctx: &Context) -> Option<Response> {
+    let obj = match call.as_object() {
+        Some(o) => o,
+        None => {
+            return Some(Response::new(
+                Value::Null,
+                Err(Error::new(INVALID_REQUEST, "request must be an object")),
+            ));
+        }
+    };
+    // a call without an id is a notification, which gets no response
+    let id = obj.get("id").cloned();
+    let response_id = id.clone().unwrap_or(Value::Null);
+    if obj.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Some(Response::new(
+            response_id,
+            Err(Error::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")),
+        ));
+    }
+    let method = match obj.get("method").and_then(Value::as_str) {
+        Some(m) => m,
+        None => {
+            return Some(Response::new(
+                response_id,
+                Err(Error::new(INVALID_REQUEST, "missing method")),
+            ));
+        }
+    };
+    let params = obj.get("params").cloned().unwrap_or(Value::Null);
+    let outcome = dispatch(method, &params, ctx);
+    id.map(|id| Response::new(id, outcome))
+}
+
+fn dispatch(method: &str, params: &Value, ctx: &Context) -> Result<Value, Error> {
+    if ctx.role < required_role(method) {
+        return Err(Error::new(UNAUTHORIZED, "admin access required"));
+    }
+    match method {
+        "getblockcount" => {
+            let chain = locks::read_chain(&ctx.blkchain);
+            Ok(json!(chain.length))
+        }
+        "getbestblockhash" => {
+            let chain = locks::read_chain(&ctx.blkchain);
+            Ok(json!(chain.tip().to_string()))
+        }
+        "getblock" => {
+            let hash: H256 = parse_param(params, 0, "hash")?;
+            let chain = locks::read_chain(&ctx.blkchain);
+            match chain.key_val.get(&hash) {
+                Some(block) => to_value(BlockInfo::from(block)),
+                None => Err(Error::new(NOT_FOUND, "block not found")),
+            }
+        }
+        "getbalance" => {
+            let address: H160 = parse_param(params, 0, "address")?;
+            let chain = locks::read_chain(&ctx.blkchain);
+            let outputs: Vec<u64> = chain
+                .current_state
+                .map
+                .values()
+                .filter(|o| o.address == address)
+                .map(|o| o.val as u64)
+                .collect();
+            to_value(BalanceInfo {
+                address: address.to_string(),
+                balance: outputs.iter().sum(),
+                outputs: outputs.len(),
+            })
+        }
+        "getrawmempool" => {
+            let pool = locks::lock_pool(&ctx.mem_pool);
+            let hashes: Vec<String> = pool.pool.keys().map(|h| h.to_string()).collect();
+            Ok(json!(hashes))
+        }
+        "sendtransaction" => {
+            let raw: String = parse_param(params, 0, "transaction")?;
+            let bytes = hex::decode(&raw)
+                .map_err(|e| Error::new(INVALID_PARAMS, &format!("invalid hex: {}", e)))?;
+            let signed: SignedTrans = bincode::deserialize(&bytes)
+                .map_err(|e| Error::new(INVALID_PARAMS, &format!("invalid transaction: {}", e)))?;
+            send_transaction(signed, ctx)
+        }
+        "getpeerinfo" => to_value(ctx.network.peers()),
+        "getmininginfo" => to_value(ctx.miner.status()),
+        "generate" => {
+            let n: usize = parse_param(params, 0, "n")?;
+            let address: Option<H160> = parse_optional_param(params, 1, "address")?;
+            let hashes = ctx
+                .miner
+                .generate(n, address)
+                .map_err(|e| Error::new(MINING_FAILED, &e))?;
+            Ok(json!(hashes.iter().map(|h| h.to_string()).collect::<Vec<_>>()))
+        }
+        _ => Err(Error::new(METHOD_NOT_FOUND, &format!("method {} not found", method))),
+    }
+}
+
+fn required_role(method: &str) -> Role {
+    match method {
+        "sendtransaction" | "generate" => Role::Admin,
+        _ => Role::ReadOnly,
+    }
+}
+
+fn send_transaction(signed: SignedTrans, ctx: &Context) -> Result<Value, Error> {
+    let hash = signed.hash();
+    if !verify(&signed.tx, &signed.public_key, &signed.signature) {
+        return Err(Error::new(TRANSACTION_REJECTED, "invalid signature"));
+    }
+    if signed.tx.output_val() > signed.tx.input_val() {
+        return Err(Error::new(TRANSACTION_REJECTED, "outputs exceed inputs"));
+    }
+    let (mut chain, mut pool) = locks::lock_both(&ctx.blkchain, &ctx.mem_pool);
+    if pool.pool.contains_key(&hash) {
+        return Err(Error::new(TRANSACTION_REJECTED, "transaction already in mempool"));
+    }
+    chain.update_state(&signed.tx);
+    pool.add(&signed);
+    drop(pool);
+    drop(chain);
+    ctx.network.broadcast(Message::NewTransactionHashes(vec![hash]));
+    Ok(json!(hash.to_string()))
+}
+
+/// Look up a parameter given either positionally or by name
+fn parse_param<T: std::str::FromStr>(params: &Value, index: usize, name: &str) -> Result<T, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    parse_optional_param(params, index, name)?
+        .ok_or_else(|| Error::new(INVALID_PARAMS, &format!("missing {}", name)))
+}
+
+/// Like `parse_param`, for a parameter that may be left out or null.
+/// Numbers are accepted as well as strings.
+fn parse_optional_param<T: std::str::FromStr>(params: &Value, index: usize, name: &str) -> Result<Option<T>, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    let value = match params {
+        Value::Array(a) => a.get(index),
+        Value::Object(o) => o.get(name),
+        _ => None,
+    };
+    let value = match value {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Number(n)) => n.to_string(),
+        Some(_) => return Err(Error::new(INVALID_PARAMS, &format!("{} must be a string or number", name))),
+    };
+    value
+        .parse::<T>()
+        .map(Some)
+        .map_err(|e| Error::new(INVALID_PARAMS, &format!("error parsing {}: {}", name, e)))
+}
+
+fn to_value<T: Serialize>(payload: T) -> Result<Value, Error> {
+    serde_json::to_value(payload).map_err(|e| Error::new(INTERNAL_ERROR, &e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::miner;
+    use crate::network::server;
+    use crate::params::ChainParams;
+
+    fn context(role: Role) -> (server::Context, miner::Context, Context) {
+        let (server_ctx, network) = server::tests::unstarted();
+        let blkchain = Arc::new(RwLock::new(Blockchain::new(ChainParams::regtest())));
+        let mem_pool = Arc::new(Mutex::new(Mempool::new()));
+        let (miner_ctx, miner) = miner::new(&network, &blkchain, &mem_pool, H160::default(), 1, None);
+        let ctx = Context {
+            blkchain,
+            mem_pool,
+            miner,
+            network,
+            role,
+        };
+        (server_ctx, miner_ctx, ctx)
+    }
+
+    fn call(body: &str, ctx: &Context) -> Value {
+        serde_json::from_str(&handle(body, ctx).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let (_server, _miner, ctx) = context(Role::Admin);
+        let resp = call("{\"jsonrpc\": \"2.0\",", &ctx);
+        assert_eq!(resp["error"]["code"], PARSE_ERROR);
+        assert_eq!(resp["id"], Value::Null);
+
+        let resp = call("[]", &ctx);
+        assert_eq!(resp["error"]["code"], INVALID_REQUEST);
+
+        let resp = call("[1]", &ctx);
+        assert_eq!(resp.as_array().unwrap().len(), 1);
+        assert_eq!(resp[0]["error"]["code"], INVALID_REQUEST);
+
+        let resp = call(r#"{"id": 1, "method": "getblockcount"}"#, &ctx);
+        assert_eq!(resp["error"]["code"], INVALID_REQUEST);
+        assert_eq!(resp["id"], 1);
+        let resp = call(r#"{"jsonrpc": "1.0", "id": 2, "method": "getblockcount"}"#, &ctx);
+        assert_eq!(resp["error"]["code"], INVALID_REQUEST);
+        assert_eq!(resp["id"], 2);
+
+        let resp = call(r#"{"jsonrpc": "2.0", "id": 3, "method": "nosuchmethod"}"#, &ctx);
+        assert_eq!(resp["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(resp["id"], 3);
+    }
+
+    #[test]
+    fn notifications_and_batches() {
+        let (_server, _miner, ctx) = context(Role::Admin);
+        assert!(handle(r#"{"jsonrpc": "2.0", "method": "getblockcount"}"#, &ctx).is_none());
+        assert!(handle(r#"[{"jsonrpc": "2.0", "method": "getblockcount"}]"#, &ctx).is_none());
+
+        // notifications are left out of the responses, invalid calls get one without an id
+        let resp = call(
+            r#"[
+                {"jsonrpc": "2.0", "id": "a", "method": "getblockcount"},
+                {"jsonrpc": "2.0", "method": "getbestblockhash"},
+                "call",
+                {"jsonrpc": "2.0", "id": "b", "method": "getblock", "params": ["xyz"]}
+            ]"#,
+            &ctx,
+        );
+        let resp = resp.as_array().unwrap();
+        assert_eq!(resp.len(), 3);
+        assert_eq!(resp[0]["id"], "a");
+        assert_eq!(resp[0]["result"], 0);
+        assert_eq!(resp[1]["id"], Value::Null);
+        assert_eq!(resp[1]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(resp[2]["id"], "b");
+        assert_eq!(resp[2]["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn read_only_callers() {
+        let (_server, _miner, ctx) = context(Role::ReadOnly);
+        let resp = call(r#"{"jsonrpc": "2.0", "id": 1, "method": "getblockcount"}"#, &ctx);
+        assert_eq!(resp["result"], 0);
+        for request in &[
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "sendtransaction", "params": ["00"]}"#,
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "generate", "params": [1]}"#,
+        ] {
+            let resp = call(request, &ctx);
+            assert_eq!(resp["error"]["code"], UNAUTHORIZED);
+        }
+        assert!(locks::lock_pool(&ctx.mem_pool).pool.is_empty());
+    }
+}
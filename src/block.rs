@@ -26,14 +26,13 @@ pub struct Block {
     pub index: usize,
 }
 
-pub fn generate_rand_block(parent: &H256) -> Block{
-    use hex_literal::hex;
+pub fn generate_rand_block(parent: &H256, difficulty: H256) -> Block{
     extern crate rand;
     use crate::crypto::merkle::MerkleTree;
     use rand::Rng;
     let mut rng = rand::thread_rng();
     let n1: u32 = rng.gen();
-    let dif = (hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
+    let dif = difficulty;
 
     let mut data = Vec::new();
     let merkle_tree = merkle::MerkleTree::new(&data);
@@ -44,6 +43,21 @@ pub fn generate_rand_block(parent: &H256) -> Block{
         index: 0, };
     single
 }
+impl Block {
+    /// The transaction paying the miner, if the block has one
+    pub fn coinbase(&self) -> Option<&SignedTrans> {
+        self.body.data.first().filter(|tx| tx.is_coinbase())
+    }
+
+    /// The signed transactions, everything but the coinbase
+    pub fn transactions(&self) -> &[SignedTrans] {
+        match self.coinbase() {
+            Some(_) => &self.body.data[1..],
+            None => &self.body.data,
+        }
+    }
+}
+
 impl Hashable for Block {
     fn hash(&self) -> H256 {
         //unimplemented!()
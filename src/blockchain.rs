@@ -1,13 +1,40 @@
 use serde::{Serialize, Deserialize};
-use super::*;
-use crate::block::{Block, generate_rand_block};
+use crate::block::Block;
 use crate::crypto::hash::{H256, Hashable, H160};
 use std::collections::{HashMap, HashSet};
-use crate::transaction::{Transaction, SignedTrans, Output, verify};
+use crate::transaction::{Transaction, Output, verify, output_id};
 use crate::state::State;
+use crate::events::{Event, EventBus};
+use crate::params::ChainParams;
+use crate::crypto::merkle::MerkleTree;
 use std::hash::Hash;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of blocks whose median time stamp a new block has to exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far in milliseconds the time stamp of a block may be ahead of our clock
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 1000;
 // use crate::block::test::generate_random_block;
 
+/// Why a block cannot be connected to the chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    /// The block breaks a consensus rule
+    Invalid(String),
+    /// The time stamp is too far ahead of our clock, the block may still become valid
+    FromFuture(u64),
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::Invalid(reason) => write!(f, "{}", reason),
+            BlockError::FromFuture(time_stamp) => write!(f, "time stamp {} is too far in the future", time_stamp),
+        }
+    }
+}
+
+/// Shared between threads behind an `RwLock`, see [`crate::locks`] for the lock order.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Blockchain {
     pub length: usize,
@@ -18,18 +45,19 @@ pub struct Blockchain {
     pub prop_time: u64,
     pub address_list: Vec<H160>,
     // pub address_pbkey: HashMap<H160, [u8]>,
+    /// Outputs each main chain block added to the current state, taken out again if a reorg
+    /// moves the block off the main chain
     pub block_state: HashMap<H256, State>,
     pub current_state: State,
+    pub params: ChainParams,
+    #[serde(skip)]
+    pub events: EventBus,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
-    pub fn new() -> Self {
-        // unimplemented!()
-        let a = [0;32];
-        let para: H256 = crypto::hash::H256::from(a);
-        let mut buf: Block = generate_rand_block(&para);
-        buf.head.time_stamp = 0;
+    /// Create a new blockchain following the given rules, only containing their genesis block
+    pub fn new(params: ChainParams) -> Self {
+        let buf = params.genesis();
         let tip:H256 = buf.hash();
         let genesis = buf.hash();
         let mut map = HashMap::new();
@@ -46,6 +74,8 @@ impl Blockchain {
             address_list: Vec::new(),
             block_state: HashMap::new(),
             current_state: State::new(),
+            params,
+            events: EventBus::new(),
         }
     }
 
@@ -57,8 +87,8 @@ impl Blockchain {
         let mut res = true;
         let mut tag = true;
         let b = block.clone();
-        let txes = b.body.data;
-        for signed in txes{
+        let txes = b.transactions();
+        for signed in txes.iter().cloned(){
             let sig = signed.get_sig();
             let pubkey = signed.get_public_key();
             for add in self.address_list.clone(){
@@ -77,6 +107,91 @@ impl Blockchain {
         }
         res
     }
+    /// Add an address to the known ones and give it the initial allocation, unless it is known
+    /// already. The allocation is the same on every node, so they agree on who may spend it.
+    pub fn allocate(&mut self, address: H160) -> bool {
+        if self.address_list.contains(&address) {
+            return false;
+        }
+        self.address_list.push(address);
+        let output = Output {
+            val: self.params.initial_allocation,
+            address,
+        };
+        let tx = Transaction {
+            id: 0,
+            tx_in: Vec::new(),
+            tx_out: vec![output.clone()],
+        };
+        self.current_state.map.insert(tx.hash(), output);
+        true
+    }
+
+    /// The proof of work target of a block on top of `parent`, which must be in the chain.
+    /// It is adjusted on the first block of every retarget window, and otherwise the parent's.
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let parent = &self.key_val[parent];
+        let window = self.params.retarget_window;
+        if window == 0 || (parent.index + 1) % window != 0 {
+            return parent.head.difficulty;
+        }
+        let mut first = parent;
+        for _ in 1..window {
+            first = &self.key_val[&first.head.block_parent];
+        }
+        let elapsed = parent.head.time_stamp.saturating_sub(first.head.time_stamp);
+        self.params.retarget(&parent.head.difficulty, elapsed)
+    }
+
+    /// The median time stamp of the last `MEDIAN_TIME_SPAN` blocks up to `parent`, which must be in
+    /// the chain. A block on top of `parent` has to be later than this.
+    pub fn median_time_past(&self, parent: &H256) -> u64 {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut block = &self.key_val[parent];
+        loop {
+            times.push(block.head.time_stamp);
+            match self.key_val.get(&block.head.block_parent) {
+                Some(b) if times.len() < MEDIAN_TIME_SPAN => block = b,
+                _ => break,
+            }
+        }
+        times.sort();
+        times[times.len() / 2]
+    }
+
+    /// Check the consensus rules that depend on the chain, for a block whose parent is in it
+    pub fn check_block(&self, block: &Block) -> Result<(), BlockError> {
+        // the hash only covers the header, the body is tied to it by the merkle root
+        if MerkleTree::new(&block.body.data).root() != block.head.mkl_root {
+            return Err(BlockError::Invalid("transactions do not match the merkle root".to_string()));
+        }
+        let median = self.median_time_past(&block.head.block_parent);
+        if block.head.time_stamp <= median {
+            return Err(BlockError::Invalid(format!("time stamp {} is not after the median {} of recent blocks", block.head.time_stamp, median)));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        if block.head.time_stamp > now + MAX_FUTURE_DRIFT {
+            return Err(BlockError::FromFuture(block.head.time_stamp));
+        }
+        let expected = self.next_difficulty(&block.head.block_parent);
+        if block.head.difficulty != expected {
+            return Err(BlockError::Invalid(format!("difficulty {} instead of {}", block.head.difficulty, expected)));
+        }
+        let num_transactions = block.transactions().len();
+        if num_transactions > self.params.max_block_size {
+            return Err(BlockError::Invalid(format!("{} transactions, at most {} are allowed", num_transactions, self.params.max_block_size)));
+        }
+        if let Some(coinbase) = block.coinbase() {
+            if coinbase.tx.tx_in[0].previous_hash != block.head.block_parent {
+                return Err(BlockError::Invalid("coinbase does not refer to the parent block".to_string()));
+            }
+            if coinbase.tx.tx_out.len() != 1 || coinbase.tx.tx_out[0].val > self.params.block_reward {
+                return Err(BlockError::Invalid(format!("coinbase must pay at most the reward of {} to one address", self.params.block_reward)));
+            }
+        }
+        Ok(())
+    }
+
     pub fn update_state(&mut self, transaction:&Transaction) {
         // let hash = block.hash();
         // self.block_state.insert(hash, State::new());
@@ -92,6 +207,11 @@ impl Blockchain {
                 st.remove(&hash);
             }
         }
+        // the outputs can be spent right away, before the transaction is in a block
+        let tx_hash = transaction.hash();
+        for (index, out) in transaction.tx_out.iter().enumerate() {
+            st.insert(output_id(&tx_hash, index), out.clone());
+        }
         self.current_state = State{map:st};
     }
     // pub fn update_state(&mut self, transaction:&Transaction, parent:&H256 ) -> State {
@@ -145,15 +265,80 @@ impl Blockchain {
         let new_head = buf.head;
         let new_body = buf.body;
         let new_blk = Block{head:new_head,body:new_body,index:new_idx};
+        let hash = new_blk.hash();
+        self.key_val.insert(hash, new_blk);
 
         if new_idx > self.length {
+            let old_tip = self.tip;
             self.length = new_idx;
-            self.tip = new_blk.hash();
+            self.tip = hash;
+            if parent == old_tip {
+                self.connect_reward(&hash);
+                self.events.publish(Event::BlockConnected {
+                    hash: self.tip.to_string(),
+                    index: new_idx,
+                });
+            } else {
+                let (disconnected, connected) = self.fork_paths(&old_tip, &hash);
+                for block in &disconnected {
+                    self.disconnect_reward(block);
+                }
+                for block in &connected {
+                    self.connect_reward(block);
+                }
+                self.events.publish(Event::Reorganized {
+                    old_tip: old_tip.to_string(),
+                    new_tip: self.tip.to_string(),
+                    index: new_idx,
+                });
+            }
         }
-        self.key_val.insert(new_blk.hash(), new_blk);
         return;
     }
 
+    /// The blocks leaving the main chain when its tip moves from `old_tip` to `new_tip`, from the
+    /// old tip down, and the blocks joining it, from the fork up to the new tip
+    fn fork_paths(&self, old_tip: &H256, new_tip: &H256) -> (Vec<H256>, Vec<H256>) {
+        let (mut old, mut new) = (*old_tip, *new_tip);
+        let (mut disconnected, mut connected) = (Vec::new(), Vec::new());
+        while old != new {
+            let (old_idx, new_idx) = (self.key_val[&old].index, self.key_val[&new].index);
+            if old_idx >= new_idx {
+                disconnected.push(old);
+                old = self.key_val[&old].head.block_parent;
+            }
+            if new_idx >= old_idx {
+                connected.push(new);
+                new = self.key_val[&new].head.block_parent;
+            }
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
+    /// Credit the reward of a block joining the main chain, so it can be spent
+    fn connect_reward(&mut self, hash: &H256) {
+        let mut credited = State::new();
+        if let Some(coinbase) = self.key_val[hash].coinbase() {
+            if let Some(reward) = coinbase.tx.tx_out.first() {
+                credited.map.insert(coinbase.hash(), reward.clone());
+            }
+        }
+        for (id, output) in &credited.map {
+            self.current_state.map.insert(*id, output.clone());
+        }
+        self.block_state.insert(*hash, credited);
+    }
+
+    /// Take back the reward of a block leaving the main chain
+    fn disconnect_reward(&mut self, hash: &H256) {
+        if let Some(credited) = self.block_state.remove(hash) {
+            for id in credited.map.keys() {
+                self.current_state.map.remove(id);
+            }
+        }
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         self.tip
@@ -174,7 +359,7 @@ mod tests {
 
     #[test]
     fn insert_one() {
-        let mut blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::new(ChainParams::mainnet());
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
         blockchain.insert(&block);
@@ -185,4 +370,127 @@ mod tests {
         assert_eq!(blockchain.tip(), block.hash());
 //        assert_eq!(blockchain.length,0);
     }
+
+    #[test]
+    fn insert_events() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet());
+        let events = blockchain.events.subscribe();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork);
+        let fork_child = generate_random_block(&fork.hash());
+        blockchain.insert(&fork_child);
+        assert_eq!(events.try_recv().unwrap(), Event::BlockConnected {
+            hash: block.hash().to_string(),
+            index: 1,
+        });
+        assert_eq!(events.try_recv().unwrap(), Event::Reorganized {
+            old_tip: block.hash().to_string(),
+            new_tip: fork_child.hash().to_string(),
+            index: 2,
+        });
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn check_block_rules() {
+        use crate::crypto::hash::generate_rand_hash160;
+        use crate::transaction::coin_base;
+
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let genesis_hash = blockchain.tip();
+        let miner = generate_rand_hash160();
+        let mut block = generate_random_block(&genesis_hash);
+        block.head.time_stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        block.head.difficulty = blockchain.next_difficulty(&genesis_hash);
+        block.body.data.push(coin_base(&miner, 10, &genesis_hash));
+        block.head.mkl_root = MerkleTree::new(&block.body.data).root();
+        assert!(blockchain.check_block(&block).is_ok());
+
+        let mut wrong = block.clone();
+        wrong.head.difficulty = generate_random_block(&genesis_hash).head.difficulty;
+        assert!(blockchain.check_block(&wrong).is_err());
+        let with_coinbase = |coinbase| {
+            let mut wrong = block.clone();
+            wrong.body.data[0] = coinbase;
+            wrong.head.mkl_root = MerkleTree::new(&wrong.body.data).root();
+            wrong
+        };
+        assert!(blockchain.check_block(&with_coinbase(coin_base(&miner, 11, &genesis_hash))).is_err());
+        assert!(blockchain.check_block(&with_coinbase(coin_base(&miner, 10, &block.hash()))).is_err());
+        // a relaying peer rewriting the body keeps the header, and so the proof of work
+        let mut wrong = block.clone();
+        wrong.body.data[0] = coin_base(&generate_rand_hash160(), 10, &genesis_hash);
+        assert_eq!(wrong.hash(), block.hash());
+        assert!(blockchain.check_block(&wrong).is_err());
+        let mut wrong = block.clone();
+        wrong.body.data.clear();
+        assert!(blockchain.check_block(&wrong).is_err());
+        let mut wrong = block.clone();
+        wrong.head.time_stamp = blockchain.median_time_past(&genesis_hash);
+        assert!(blockchain.check_block(&wrong).is_err());
+        let mut wrong = block.clone();
+        wrong.head.time_stamp += MAX_FUTURE_DRIFT + 60 * 1000;
+        assert_eq!(blockchain.check_block(&wrong), Err(BlockError::FromFuture(wrong.head.time_stamp)));
+
+        // the reward is spendable once the block is connected
+        blockchain.insert(&block);
+        let coinbase = block.coinbase().unwrap().hash();
+        assert_eq!(blockchain.current_state.map[&coinbase].address, miner);
+    }
+
+    #[test]
+    fn reorg_moves_rewards() {
+        use crate::crypto::hash::generate_rand_hash160;
+        use crate::transaction::coin_base;
+
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let genesis_hash = blockchain.tip();
+        let with_reward = |parent: &H256| {
+            let mut block = generate_random_block(parent);
+            block.body.data.push(coin_base(&generate_rand_hash160(), 10, parent));
+            block
+        };
+        let block = with_reward(&genesis_hash);
+        blockchain.insert(&block);
+        // a side fork of the same length earns nothing
+        let fork = with_reward(&genesis_hash);
+        blockchain.insert(&fork);
+        let reward = |b: &Block| b.coinbase().unwrap().hash();
+        assert!(blockchain.current_state.map.contains_key(&reward(&block)));
+        assert!(!blockchain.current_state.map.contains_key(&reward(&fork)));
+
+        // until it becomes the main chain
+        let fork_child = with_reward(&fork.hash());
+        blockchain.insert(&fork_child);
+        assert!(!blockchain.current_state.map.contains_key(&reward(&block)));
+        assert!(blockchain.current_state.map.contains_key(&reward(&fork)));
+        assert!(blockchain.current_state.map.contains_key(&reward(&fork_child)));
+    }
+
+    #[test]
+    fn allocate_and_spend() {
+        use crate::crypto::hash::generate_rand_hash160;
+        use crate::transaction::{Input, Output};
+
+        let mut blockchain = Blockchain::new(ChainParams::mainnet());
+        let (alice, bob) = (generate_rand_hash160(), generate_rand_hash160());
+        assert!(blockchain.allocate(alice));
+        assert!(!blockchain.allocate(alice));
+        let (&allocation, output) = blockchain.current_state.map.iter().next().unwrap();
+        assert_eq!((output.address, output.val), (alice, 10));
+
+        let tx = Transaction {
+            id: 7,
+            tx_in: vec![Input { val: 10, previous_hash: allocation }],
+            tx_out: vec![Output { val: 4, address: bob }, Output { val: 6, address: alice }],
+        };
+        blockchain.update_state(&tx);
+        let state = &blockchain.current_state.map;
+        assert!(!state.contains_key(&allocation));
+        assert_eq!(state[&output_id(&tx.hash(), 0)].address, bob);
+        assert_eq!(state[&output_id(&tx.hash(), 1)].val, 6);
+    }
 }
@@ -0,0 +1,186 @@
+use crate::crypto::hash::{H160, H256};
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Node settings, read from the file given with `--config`.
+/// Every field is optional in the file, and flags given on the command line take precedence.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub p2p: SocketAddr,
+    pub api: SocketAddr,
+    /// Peers to stay connected to
+    pub connect: Vec<SocketAddr>,
+    pub p2p_workers: usize,
+    /// Directory for files the node keeps, such as the peer addresses
+    pub data_dir: Option<PathBuf>,
+    pub peers_file: Option<PathBuf>,
+    pub outbound: usize,
+    pub max_incoming: usize,
+    pub max_outgoing: usize,
+    /// Maximum rate in KiB/s data is read from each peer
+    pub max_peer_rate: u64,
+    pub encrypt: bool,
+    pub identity_key: Option<PathBuf>,
+    /// IP addresses whose peers must prove an identity key, as IP=HEXKEY
+    pub trust: Vec<String>,
+    pub network: String,
+    pub api_token: Option<String>,
+    pub api_read_token: Option<String>,
+    pub api_auth_file: Option<PathBuf>,
+    pub api_allow: Vec<IpAddr>,
+    pub log_level: LogLevel,
+    pub mining: Mining,
+    pub consensus: Consensus,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mining {
+    /// Threads searching for a nonce in parallel
+    pub threads: usize,
+    #[serde(deserialize_with = "from_str")]
+    pub reward_address: Option<H160>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Consensus {
+    /// Proof of work target of the genesis block, instead of the network's
+    #[serde(deserialize_with = "from_str")]
+    pub difficulty: Option<H256>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// The verbosity for `stderrlog`, the same as passing `-v` this many times
+    pub fn verbosity(self) -> usize {
+        self as usize
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("unknown log level {}", s)),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            p2p: ([127, 0, 0, 1], 6000).into(),
+            api: ([127, 0, 0, 1], 7000).into(),
+            connect: vec![],
+            p2p_workers: 4,
+            data_dir: None,
+            peers_file: None,
+            outbound: 8,
+            max_incoming: 125,
+            max_outgoing: 16,
+            max_peer_rate: 1024,
+            encrypt: false,
+            identity_key: None,
+            trust: vec![],
+            network: "mainnet".to_string(),
+            api_token: None,
+            api_read_token: None,
+            api_auth_file: None,
+            api_allow: vec![],
+            log_level: LogLevel::Error,
+            mining: Mining::default(),
+            consensus: Consensus::default(),
+        }
+    }
+}
+
+impl Default for Mining {
+    fn default() -> Self {
+        Mining {
+            threads: 1,
+            reward_address: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read the settings from a JSON file
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        serde_json::from_str(&content)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Where the peer addresses are saved, in the data directory unless set explicitly
+    pub fn peers_file(&self) -> Option<PathBuf> {
+        self.peers_file
+            .clone()
+            .or_else(|| self.data_dir.as_ref().map(|dir| dir.join("peers.json")))
+    }
+}
+
+/// Deserialize an optional value from its string form, like a hex hash
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => s.parse().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_partial_file() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "p2p": "0.0.0.0:6001",
+                "connect": ["10.0.0.2:6000"],
+                "data_dir": "/var/lib/node",
+                "log_level": "info",
+                "mining": { "threads": 4, "reward_address": "00112233445566778899aabbccddeeff00112233" },
+                "consensus": { "difficulty": "00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.p2p, "0.0.0.0:6001".parse().unwrap());
+        assert_eq!(config.connect.len(), 1);
+        assert_eq!(config.log_level.verbosity(), 2);
+        assert_eq!(config.mining.threads, 4);
+        assert!(config.mining.reward_address.is_some());
+        assert!(config.consensus.difficulty.is_some());
+        assert_eq!(config.peers_file(), Some(PathBuf::from("/var/lib/node/peers.json")));
+        // unset fields keep their defaults
+        assert_eq!(config.p2p_workers, 4);
+        assert_eq!(config.api, Config::default().api);
+
+        assert!(serde_json::from_str::<Config>(r#"{ "p2p_worker": 2 }"#).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{ "mining": { "reward_address": "xyz" } }"#).is_err());
+    }
+}
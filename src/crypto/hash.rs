@@ -65,6 +65,43 @@ impl std::fmt::Display for H256 {
     }
 }
 
+impl std::fmt::Display for H160 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:>02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        if bytes.len() != 32 {
+            return Err(format!("expected 32 bytes, got {}", bytes.len()));
+        }
+        let mut buffer: [u8; 32] = [0; 32];
+        buffer.copy_from_slice(&bytes);
+        Ok(H256(buffer))
+    }
+}
+
+impl std::str::FromStr for H160 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        if bytes.len() != 20 {
+            return Err(format!("expected 20 bytes, got {}", bytes.len()));
+        }
+        let mut buffer: [u8; 20] = [0; 20];
+        buffer.copy_from_slice(&bytes);
+        Ok(H160(buffer))
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -169,4 +206,14 @@ pub mod tests {
         (&raw_bytes).into()
     }
 
+    #[test]
+    fn hex_round_trip() {
+        use super::H160;
+        let hash = generate_random_hash();
+        assert_eq!(hash.to_string().parse::<H256>().unwrap(), hash);
+        let address = H160::hash(hash.as_ref());
+        assert_eq!(address.to_string().parse::<H160>().unwrap(), address);
+        assert!("00ff".parse::<H256>().is_err());
+    }
+
 }
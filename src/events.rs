@@ -0,0 +1,110 @@
+use serde::Serialize;
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of recent events kept for long-polling clients
+const EVENT_LOG_SIZE: usize = 1024;
+/// Events buffered per in-process subscriber before new ones are dropped
+const SUBSCRIBER_QUEUE_SIZE: usize = 1024;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A block was appended to the current tip
+    BlockConnected { hash: String, index: usize },
+    /// A block on another branch became the new tip
+    Reorganized { old_tip: String, new_tip: String, index: usize },
+    TransactionAdded { hash: String },
+    TransactionRemoved { hash: String },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Record {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+struct Inner {
+    next_seq: u64,
+    log: VecDeque<Record>,
+    subscribers: Vec<Sender<Event>>,
+}
+
+/// Fan-out of chain and mempool events to in-process subscribers and long-polling clients.
+#[derive(Clone)]
+pub struct EventBus {
+    inner: Arc<(Mutex<Inner>, Condvar)>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
+
+impl std::fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let inner = self.inner.0.lock().unwrap();
+        write!(f, "EventBus {{ next_seq: {}, subscribers: {} }}", inner.next_seq, inner.subscribers.len())
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let inner = Inner {
+            next_seq: 1,
+            log: VecDeque::with_capacity(EVENT_LOG_SIZE),
+            subscribers: Vec::new(),
+        };
+        EventBus {
+            inner: Arc::new((Mutex::new(inner), Condvar::new())),
+        }
+    }
+
+    /// Receive every event published from now on. Events are dropped for a subscriber that falls behind.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel::bounded(SUBSCRIBER_QUEUE_SIZE);
+        self.inner.0.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        let (lock, cvar) = &*self.inner;
+        let mut inner = lock.lock().unwrap();
+        inner.subscribers.retain(|s| match s.try_send(event.clone()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        if inner.log.len() == EVENT_LOG_SIZE {
+            inner.log.pop_front();
+        }
+        inner.log.push_back(Record { seq, event });
+        cvar.notify_all();
+    }
+
+    /// Sequence number the next published event will get
+    pub fn next_seq(&self) -> u64 {
+        self.inner.0.lock().unwrap().next_seq
+    }
+
+    /// Return the logged events with a sequence number of at least `since`,
+    /// waiting up to `timeout` for one to be published if there are none yet.
+    pub fn poll(&self, since: u64, timeout: Duration) -> Vec<Record> {
+        let (lock, cvar) = &*self.inner;
+        let deadline = Instant::now() + timeout;
+        let mut inner = lock.lock().unwrap();
+        while inner.next_seq <= since {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            inner = cvar.wait_timeout(inner, deadline - now).unwrap().0;
+        }
+        inner.log.iter().filter(|r| r.seq >= since).cloned().collect()
+    }
+}
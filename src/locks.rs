@@ -0,0 +1,158 @@
+//! Locking of the state shared between the workers, the miner and the API.
+//!
+//! The chain is always locked before the mempool: a thread may take the mempool while holding the
+//! chain, but never the chain while holding the mempool, and never the mempool twice. All locking
+//! of the two goes through these functions, which check the order in debug builds.
+
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+thread_local! {
+    /// Whether this thread holds the mempool
+    static POOL_HELD: Cell<bool> = Cell::new(false);
+}
+
+fn check_pool_not_held(what: &str) {
+    debug_assert!(
+        !POOL_HELD.with(Cell::get),
+        "lock order violated: {} while holding the mempool",
+        what
+    );
+}
+
+/// The locked mempool, remembering that this thread holds it until dropped.
+pub struct PoolGuard<'a> {
+    guard: MutexGuard<'a, Mempool>,
+}
+
+impl Deref for PoolGuard<'_> {
+    type Target = Mempool;
+
+    fn deref(&self) -> &Mempool {
+        &self.guard
+    }
+}
+
+impl DerefMut for PoolGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Mempool {
+        &mut self.guard
+    }
+}
+
+impl Drop for PoolGuard<'_> {
+    fn drop(&mut self) {
+        POOL_HELD.with(|held| held.set(false));
+    }
+}
+
+pub fn read_chain(chain: &RwLock<Blockchain>) -> RwLockReadGuard<'_, Blockchain> {
+    check_pool_not_held("locking the chain");
+    chain.read().unwrap()
+}
+
+pub fn write_chain(chain: &RwLock<Blockchain>) -> RwLockWriteGuard<'_, Blockchain> {
+    check_pool_not_held("locking the chain");
+    chain.write().unwrap()
+}
+
+pub fn lock_pool(pool: &Mutex<Mempool>) -> PoolGuard<'_> {
+    check_pool_not_held("locking the mempool again");
+    let guard = pool.lock().unwrap();
+    POOL_HELD.with(|held| held.set(true));
+    PoolGuard { guard }
+}
+
+/// Lock the chain for writing and the mempool, in order, for updates that must change both at once
+pub fn lock_both<'a>(
+    chain: &'a RwLock<Blockchain>,
+    mempool: &'a Mutex<Mempool>,
+) -> (RwLockWriteGuard<'a, Blockchain>, PoolGuard<'a>) {
+    let chain = write_chain(chain);
+    (chain, lock_pool(mempool))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashable;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::params::ChainParams;
+    use crate::transaction::{gen_rand_signtx, SignedTrans};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn concurrent_blocks_and_transactions() {
+        let chain = Arc::new(RwLock::new(Blockchain::new(ChainParams::mainnet())));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let mut threads = Vec::new();
+        // transactions enter the mempool and the state together, like a relayed transaction
+        for _ in 0..4 {
+            let (chain, mempool) = (Arc::clone(&chain), Arc::clone(&mempool));
+            threads.push(thread::spawn(move || {
+                for _ in 0..50 {
+                    let tx = gen_rand_signtx();
+                    let (mut chain, mut pool) = lock_both(&chain, &mempool);
+                    chain.update_state(&tx.tx);
+                    pool.add(&tx);
+                }
+            }));
+        }
+        // blocks take transactions out of the mempool, like the miner and block relay
+        for _ in 0..2 {
+            let (chain, mempool) = (Arc::clone(&chain), Arc::clone(&mempool));
+            threads.push(thread::spawn(move || {
+                for _ in 0..50 {
+                    let mut chain = write_chain(&chain);
+                    let mut block = generate_random_block(&chain.tip());
+                    let mut pool = lock_pool(&mempool);
+                    block.body.data = pool.pool.values().take(3).cloned().collect();
+                    for tx in &block.body.data {
+                        pool.remove(tx);
+                    }
+                    drop(pool);
+                    block.head.mkl_root = MerkleTree::new(&block.body.data).root();
+                    chain.insert(&block);
+                }
+            }));
+        }
+        // readers, like the API and block requests
+        for _ in 0..2 {
+            let (chain, mempool) = (Arc::clone(&chain), Arc::clone(&mempool));
+            threads.push(thread::spawn(move || {
+                for _ in 0..200 {
+                    let chain = read_chain(&chain);
+                    let _ = chain.key_val.get(&chain.tip());
+                    let _ = lock_pool(&mempool).pool.len();
+                }
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let chain = read_chain(&chain);
+        assert_eq!(chain.get_num(), 101);
+        assert_eq!(chain.length, 100);
+        let mined: Vec<&SignedTrans> = chain.key_val.values().flat_map(|b| b.body.data.iter()).collect();
+        let pool = lock_pool(&mempool);
+        // every transaction is either in a block or still in the mempool, never both
+        assert_eq!(mined.len() + pool.pool.len(), 200);
+        let mined: HashSet<_> = mined.iter().map(|tx| tx.hash()).collect();
+        assert!(pool.pool.keys().all(|hash| !mined.contains(hash)));
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order violated")]
+    fn chain_after_pool_is_caught() {
+        let chain = RwLock::new(Blockchain::new(ChainParams::mainnet()));
+        let mempool = Mutex::new(Mempool::new());
+        let _pool = lock_pool(&mempool);
+        let _chain = write_chain(&chain);
+    }
+}
@@ -5,154 +5,366 @@ extern crate hex_literal;
 pub mod api;
 pub mod block;
 pub mod blockchain;
+pub mod config;
 pub mod crypto;
+pub mod events;
 pub mod miner;
 pub mod network;
+pub mod params;
 pub mod transaction;
 pub mod state;
 pub mod mempool;
+pub mod locks;
+pub mod shutdown;
+pub mod txgen;
 
 use clap::clap_app;
-use crossbeam::channel;
 use log::{error, info};
 use api::Server as ApiServer;
-use network::{server, worker};
+use network::{addrman, handshake, outbound, queue, server, transport, worker};
 use std::net;
 use std::process;
-use std::thread;
-use std::time;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use hex_literal::hex;
 use crate::mempool::Mempool;
+use crate::crypto::hash::H160;
 use crate::crypto::key_pair;
 use ring::signature::KeyPair;
-use crate::crypto::hash::H160;
 
 fn main() {
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
      (version: "0.1")
      (about: "Bitcoin client")
+     (@arg config: --config [FILE] "Reads settings from a JSON file, flags given here take precedence")
      (@arg verbose: -v ... "Increases the verbosity of logging")
-     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
-     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
-     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
-     (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg log_level: --("log-level") [LEVEL] "Sets the log level: error, warn, info, debug or trace (default error)")
+     (@arg peer_addr: --p2p [ADDR] "Sets the IP address and the port of the P2P server (default 127.0.0.1:6000)")
+     (@arg api_addr: --api [ADDR] "Sets the IP address and the port of the API server (default 127.0.0.1:7000)")
+     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to stay connected to")
+     (@arg p2p_workers: --("p2p-workers") [INT] "Sets the number of worker threads for P2P server (default 4)")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory files kept by the node are stored in")
+     (@arg peers_file: --("peers-file") [FILE] "Sets the file known peer addresses are saved to (default peers.json in the data directory)")
+     (@arg outbound: --outbound [INT] "Sets the number of outgoing peers to keep connected (default 8)")
+     (@arg max_incoming: --("max-incoming") [INT] "Sets the maximum number of incoming peers (default 125)")
+     (@arg max_outgoing: --("max-outgoing") [INT] "Sets the maximum number of outgoing peers (default 16)")
+     (@arg max_peer_rate: --("max-peer-rate") [KBPS] "Sets the maximum rate in KiB/s data is read from each peer (default 1024)")
+     (@arg encrypt: --encrypt "Encrypts connections with peers that support it")
+     (@arg identity_key: --("identity-key") [FILE] "Sets the file holding the key that identifies this node to peers, created if missing")
+     (@arg trust: --trust ... [PEER] "Requires peers at an IP address to prove an identity key, given as IP=HEXKEY")
+     (@arg network: --network [NETWORK] "Sets the network to join: mainnet, testnet or regtest (default mainnet)")
+     (@arg mining_threads: --("mining-threads") [INT] "Sets the number of threads searching for blocks (default 1)")
+     (@arg reward_address: --("reward-address") [ADDR] "Sets the address mining rewards are paid to")
+     (@arg api_token: --("api-token") [TOKEN] "Sets a bearer token granting admin access to the API server")
+     (@arg api_read_token: --("api-read-token") [TOKEN] "Sets a bearer token granting read-only access to the API server")
+     (@arg api_auth_file: --("api-auth-file") [FILE] "Reads API server credentials from a file")
+     (@arg api_allow: --("api-allow") ... [IP] "Only serves API requests from these addresses")
     )
     .get_matches();
 
+    // read the config file, the logger is set up from it before errors can be reported
+    let loaded = match matches.value_of("config") {
+        Some(path) => config::Config::load(std::path::Path::new(path))
+            .map_err(|e| format!("Error reading config file {}: {}", path, e)),
+        None => Ok(config::Config::default()),
+    };
+    let log_level = parse_flag::<config::LogLevel>(&matches, "log_level", "log level");
+
     // init logger
-    let verbosity = matches.occurrences_of("verbose") as usize;
+    let verbosity = match matches.occurrences_of("verbose") as usize {
+        0 => log_level
+            .or_else(|| loaded.as_ref().ok().map(|c| c.log_level))
+            .map(config::LogLevel::verbosity)
+            .unwrap_or(0),
+        v => v,
+    };
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
-    // parse p2p server address
-    let p2p_addr = matches
-        .value_of("peer_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P server address: {}", e);
+    let mut config = loaded.unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    // flags override the config file
+    if let Some(addr) = parse_flag(&matches, "peer_addr", "P2P server address") {
+        config.p2p = addr;
+    }
+    if let Some(addr) = parse_flag(&matches, "api_addr", "API server address") {
+        config.api = addr;
+    }
+    if let Some(peers) = parse_flags(&matches, "known_peer", "peer address") {
+        config.connect = peers;
+    }
+    if let Some(workers) = parse_flag(&matches, "p2p_workers", "P2P workers") {
+        config.p2p_workers = workers;
+    }
+    if let Some(dir) = matches.value_of("data_dir") {
+        config.data_dir = Some(dir.into());
+    }
+    if let Some(path) = matches.value_of("peers_file") {
+        config.peers_file = Some(path.into());
+    }
+    if let Some(outbound) = parse_flag(&matches, "outbound", "outbound peers") {
+        config.outbound = outbound;
+    }
+    if let Some(max) = parse_flag(&matches, "max_incoming", "max incoming peers") {
+        config.max_incoming = max;
+    }
+    if let Some(max) = parse_flag(&matches, "max_outgoing", "max outgoing peers") {
+        config.max_outgoing = max;
+    }
+    if let Some(rate) = parse_flag(&matches, "max_peer_rate", "max peer rate") {
+        config.max_peer_rate = rate;
+    }
+    if matches.is_present("encrypt") {
+        config.encrypt = true;
+    }
+    if let Some(path) = matches.value_of("identity_key") {
+        config.identity_key = Some(path.into());
+    }
+    if let Some(trusted) = matches.values_of("trust") {
+        config.trust = trusted.map(String::from).collect();
+    }
+    if let Some(network) = matches.value_of("network") {
+        config.network = network.to_string();
+    }
+    if let Some(threads) = parse_flag(&matches, "mining_threads", "mining threads") {
+        config.mining.threads = threads;
+    }
+    if let Some(address) = parse_flag(&matches, "reward_address", "reward address") {
+        config.mining.reward_address = Some(address);
+    }
+    if let Some(token) = matches.value_of("api_token") {
+        config.api_token = Some(token.to_string());
+    }
+    if let Some(token) = matches.value_of("api_read_token") {
+        config.api_read_token = Some(token.to_string());
+    }
+    if let Some(path) = matches.value_of("api_auth_file") {
+        config.api_auth_file = Some(path.into());
+    }
+    if let Some(ips) = parse_flags(&matches, "api_allow", "API allowed address") {
+        config.api_allow = ips;
+    }
+
+    let p2p_addr = config.p2p;
+    let api_addr = config.api;
+
+    // parse network
+    let network = config.network.parse::<handshake::Network>().unwrap_or_else(|e| {
+        error!("Error parsing network: {}", e);
+        process::exit(1);
+    });
+
+    if config.mining.threads == 0 {
+        error!("At least one mining thread is needed");
+        process::exit(1);
+    }
+
+    // the burst a peer may send has to fit in the rate limiter too
+    let max_inbound_rate = match config.max_peer_rate.checked_mul(1024) {
+        Some(rate) if rate > 0 && rate.checked_mul(server::INBOUND_BURST_SECS).is_some() => rate,
+        _ => {
+            error!("Invalid max peer rate {} KiB/s, it has to be positive and not overflow", config.max_peer_rate);
+            process::exit(1);
+        }
+    };
+
+    if let Some(dir) = &config.data_dir {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| {
+            error!("Error creating data directory {}: {}", dir.display(), e);
             process::exit(1);
         });
+    }
 
-    // parse api server address
-    let api_addr = matches
-        .value_of("api_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing API server address: {}", e);
+    // parse api server credentials and restrictions
+    let mut api_auth = api::auth::Config::default();
+    if let Some(token) = &config.api_token {
+        api_auth.add_token(token, api::auth::Role::Admin);
+    }
+    if let Some(token) = &config.api_read_token {
+        api_auth.add_token(token, api::auth::Role::ReadOnly);
+    }
+    if let Some(path) = &config.api_auth_file {
+        api_auth.load_file(&path.to_string_lossy()).unwrap_or_else(|e| {
+            error!("Error reading API credentials from {}: {}", path.display(), e);
             process::exit(1);
         });
+    }
+    for ip in &config.api_allow {
+        api_auth.allow_ip(*ip);
+    }
 
-    let mut blockchain = blockchain::Blockchain::new();
+    let mut chain_params = params::ChainParams::for_network(network);
+    if let Some(difficulty) = config.consensus.difficulty {
+        chain_params.genesis_difficulty = difficulty;
+    }
+    let mut blockchain = blockchain::Blockchain::new(chain_params);
+    // the wallet holds the node's own coins, starting with its allocation
+    let key = key_pair::random();
+    let wallet_address = H160::hash(key.public_key().as_ref());
+    blockchain.allocate(wallet_address);
+    info!("Wallet address {}", wallet_address);
     let mut mempool = mempool::Mempool::new();
+    let events = events::EventBus::new();
+    blockchain.events = events.clone();
+    mempool.events = events.clone();
     println!("{:}",blockchain.tip);
-    let mut blkchain = Arc::new(Mutex::new(blockchain));
+    let mut blkchain = Arc::new(RwLock::new(blockchain));
     let mut mem_pool = Arc::new(Mutex::new(mempool));
     let mut address_list = Arc::new(Mutex::new(Vec::new()));
-    let key = key_pair::random();
-    let public_key = key.public_key();
-    let byte_pbkey = public_key.as_ref();
-    let address = H160::hash(&byte_pbkey);
-    // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::unbounded();
+    // load known peer addresses
+    let addrman = addrman::AddrManager::new(Some(p2p_addr), config.peers_file()).unwrap_or_else(|e| {
+        error!("Error loading peer addresses: {}", e);
+        process::exit(1);
+    });
+    let addrman = Arc::new(Mutex::new(addrman));
+    let outbound_peers = config.outbound;
+    let limits = server::ConnectionLimits {
+        max_incoming: config.max_incoming,
+        max_outgoing: config.max_outgoing,
+        max_inbound_rate,
+    };
+    let known_peers = config.connect.clone();
+
+    // create the prioritized queue between server and workers
+    let (msg_tx, msg_rx) = queue::new(queue::LANE_CAPACITY);
 
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    let height_chain = Arc::clone(&blkchain);
+    let mut local = handshake::Local::new(
+        network,
+        Box::new(move || locks::read_chain(&height_chain).length as u64),
+    );
+    local.encrypt = config.encrypt;
+    if let Some(path) = &config.identity_key {
+        let identity = transport::Identity::load_or_generate(path).unwrap_or_else(|e| {
+            error!("Error loading identity key from {}: {}", path.display(), e);
+            process::exit(1);
+        });
+        info!("Node identity key {}", hex::encode(identity.public_key()));
+        local.identity = Some(identity);
+    }
+    for entry in &config.trust {
+        let parsed = entry.find('=').and_then(|i| {
+            // a port is accepted for compatibility, but the pin covers the whole address
+            let ip = entry[..i]
+                .parse::<net::IpAddr>()
+                .or_else(|_| entry[..i].parse::<net::SocketAddr>().map(|addr| addr.ip()))
+                .ok()?;
+            let key = hex::decode(&entry[i + 1..]).ok()?;
+            Some((ip, key))
+        });
+        let (ip, key) = parsed.unwrap_or_else(|| {
+            error!("Error parsing trusted peer {}, expected IP=HEXKEY", entry);
+            process::exit(1);
+        });
+        local.trusted.insert(ip, key);
+    }
+    if !local.trusted.is_empty() && !local.encrypt {
+        error!("Trusted peers can only prove their identity over encrypted connections, add --encrypt");
+        process::exit(1);
+    }
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, local, &addrman, limits).unwrap();
     server_ctx.start().unwrap();
 
     // start the worker
-    let p2p_workers = matches
-        .value_of("p2p_workers")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P workers: {}", e);
-            process::exit(1);
-        });
     let worker_ctx = worker::new(
-        p2p_workers,
+        config.p2p_workers,
         msg_rx,
         &server,
         &blkchain,
         &mem_pool,
-        &address_list
+        &address_list,
+        &addrman,
     );
-    worker_ctx.start();
+    let workers = worker_ctx.start();
 
     // start the miner
     let (miner_ctx, miner) = miner::new(
         &server,
         &blkchain,
         &mem_pool,
-        key,
-        &address
+        wallet_address,
+        config.mining.threads,
+        config.mining.reward_address,
     );
-    miner_ctx.start();
-
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
-        });
-    }
+    let miner_thread = miner_ctx.start();
+
+    // start the transaction generator, stopped until asked to run
+    let (txgen_ctx, txgen) = txgen::new(&server, &blkchain, &mem_pool, key);
+    let txgen_thread = txgen_ctx.start();
 
+    // keep up the number of outgoing peers
+    // known peers are reconnected whenever they drop
+    let outbound_ctx = outbound::new(&server, &addrman, outbound_peers, &known_peers);
+    outbound_ctx.start();
+
+    // shut down cleanly on SIGINT or SIGTERM, a second signal exits immediately
+    let (shutdown_ctx, shutdown) = shutdown::new();
+    let signal_shutdown = shutdown.clone();
+    ctrlc::set_handler(move || {
+        if !signal_shutdown.request("received a signal") {
+            process::exit(shutdown::EXIT_FORCED);
+        }
+    })
+    .unwrap_or_else(|e| {
+        error!("Error installing signal handler: {}", e);
+        process::exit(1);
+    });
 
     // start the API server
     ApiServer::start(
         api_addr,
         &miner,
+        &txgen,
         &server,
+        &blkchain,
+        &mem_pool,
+        &events,
+        api_auth,
+        &shutdown,
     );
 
-    loop {
-        std::thread::park();
-    }
+    let status = shutdown_ctx.wait(shutdown::Subsystems {
+        txgen,
+        txgen_thread,
+        miner,
+        miner_thread,
+        server,
+        workers,
+        addrman,
+    });
+    process::exit(status);
+}
+
+/// Parse the value of a flag if it was given, exiting on errors
+fn parse_flag<T>(matches: &clap::ArgMatches, name: &str, what: &str) -> Option<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    matches.value_of(name).map(|value| parse_or_exit(value, what))
+}
+
+/// Parse the values of a flag given multiple times if it was given, exiting on errors
+fn parse_flags<T>(matches: &clap::ArgMatches, name: &str, what: &str) -> Option<Vec<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    matches
+        .values_of(name)
+        .map(|values| values.map(|value| parse_or_exit(value, what)).collect())
+}
+
+fn parse_or_exit<T>(value: &str, what: &str) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse::<T>().unwrap_or_else(|e| {
+        error!("Error parsing {} {}: {}", what, value, e);
+        process::exit(1);
+    })
 }
@@ -2,35 +2,38 @@ use serde::{Serialize,Deserialize};
 use std::collections::HashMap;
 use crate::crypto::hash::{H256, Hashable};
 use crate::transaction::SignedTrans;
+use crate::events::{Event, EventBus};
 
 
+/// Shared between threads behind a `Mutex`, see [`crate::locks`] for the lock order.
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Mempool {
     pub pool: HashMap<H256, SignedTrans>,
+    #[serde(skip)]
+    pub events: EventBus,
 }
 
 impl Mempool {
     pub fn new() -> Self{
         let m =Mempool {
-            pool: HashMap::new()
+            pool: HashMap::new(),
+            events: EventBus::new(),
         };
         m
     }
 
     pub fn add(&mut self, signed: &SignedTrans) {
-        let map = self.clone().pool;
         let hash = signed.hash();
-        if !map.contains_key(&hash){
+        if !self.pool.contains_key(&hash){
             self.pool.insert(hash, signed.clone());
+            self.events.publish(Event::TransactionAdded { hash: hash.to_string() });
         };
     }
 
     pub fn remove(&mut self, signed: &SignedTrans) {
-        let map = self.clone().pool;
         let hash = signed.hash();
-        if map.contains_key(&hash) {
-            let res = self.pool.remove(&hash);
+        if self.pool.remove(&hash).is_some() {
+            self.events.publish(Event::TransactionRemoved { hash: hash.to_string() });
         }
-        return
     }
 }
@@ -2,36 +2,38 @@ use crate::network::server::Handle as ServerHandle;
 
 use log::info;
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 
 use std::thread;
-use crate::transaction::{Transaction, generate_rand_transaction, SignedTrans, sign, Input, Output, gen_rand_signtx};
+use crate::transaction;
 use crate::mempool::Mempool;
 use rand::Rng;
 use crate::crypto::merkle::MerkleTree;
-use crate::block::{Block, Header, Content};
-use std::time::{SystemTime, UNIX_EPOCH, Instant};
-use crate::crypto::hash::{Hashable, generate_rand_hash256, H160, H256};
-use std::sync::{Arc, Mutex};
+use crate::block::Block;
+use std::time::Instant;
+use crate::crypto::hash::{Hashable, H160, H256};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use crate::blockchain::Blockchain;
-use hex_literal::hex;
+use crate::locks;
 use crate::block;
-use crate::network::message::Message::NewBlockHashes;
 use crate::network::message::Message;
-use crate::crypto::key_pair::random;
-use crate::crypto::key_pair;
-use ring::signature::{KeyPair, Ed25519KeyPair};
-use crate::network::peer::Direction::Outgoing;
-use std::collections::HashMap;
-use crate::state::State;
-use url::quirks::hash;
-use std::thread::{sleep, current};
+use serde::Serialize;
 
+/// Number of nonces a mining thread tries between checks whether to stop
+const HASH_REPORT_INTERVAL: u64 = 1024;
+/// How often the hash rate is updated and control signals are checked while mining
+const STATUS_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// Most blocks one generate request may ask for
+pub const MAX_GENERATE: usize = 1000;
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    Pause,
     Exit,
+    /// Mine this many blocks on the tip, paying the address if given, and reply with their hashes
+    Generate(usize, Option<H160>, Sender<Result<Vec<H256>, String>>),
 }
 
 enum OperatingState {
@@ -40,31 +42,97 @@ enum OperatingState {
     ShutDown,
 }
 
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MinerState {
+    Paused,
+    Running,
+    Shutdown,
+}
+
+/// The block the miner is currently searching a nonce for.
+#[derive(Serialize, Debug, Clone)]
+pub struct Template {
+    pub parent: String,
+    pub index: usize,
+    pub difficulty: String,
+    pub num_transactions: usize,
+}
+
+/// Snapshot of the miner, shared between the miner thread and its handles.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub state: MinerState,
+    /// Delay in microseconds between mining iterations
+    pub lambda: u64,
+    pub blocks_mined: u64,
+    pub hashes: u64,
+    /// Hashes per second since the miner was last started
+    pub hashrate: f64,
+    /// Threads searching for a nonce in parallel
+    pub threads: usize,
+    pub reward_address: Option<String>,
+    pub template: Option<Template>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status {
+            state: MinerState::Paused,
+            lambda: 0,
+            blocks_mined: 0,
+            hashes: 0,
+            hashrate: 0.0,
+            threads: 1,
+            reward_address: None,
+            template: None,
+        }
+    }
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    blkchain: Arc<Mutex<Blockchain>>,
+    blkchain: Arc<RwLock<Blockchain>>,
     mem_pool: Arc<Mutex<Mempool>>,
-    key: Ed25519KeyPair,
+    /// The node's wallet address
     self_address:H160,
+    /// Where block rewards are paid, our own address if not set
+    reward_address: Option<H160>,
+    threads: usize,
+    /// Whether blocks are being generated on request
+    generating: bool,
+    /// Set by a pause or exit to end the generation in progress
+    generate_interrupted: bool,
+    status: Arc<Mutex<Status>>,
+    /// When the miner last entered the running state, and the hashes tried since
+    run_since: Instant,
+    run_hashes: u64,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
 }
 
 pub fn new(
     server: &ServerHandle,
-    blkchain: &Arc<Mutex<Blockchain>>,
+    blkchain: &Arc<RwLock<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
-    key_pair: Ed25519KeyPair,
-    self_address: &H160
+    self_address: H160,
+    threads: usize,
+    reward_address: Option<H160>,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let status = Arc::new(Mutex::new(Status {
+        threads,
+        reward_address: reward_address.map(|a| a.to_string()),
+        ..Status::default()
+    }));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -72,39 +140,72 @@ pub fn new(
         server: server.clone(),
         blkchain: Arc::clone(blkchain),
         mem_pool: Arc::clone(mempool),
-        key: key_pair,
-        self_address: self_address.clone(),
+        self_address,
+        reward_address,
+        threads,
+        generating: false,
+        generate_interrupted: false,
+        status: Arc::clone(&status),
+        run_since: Instant::now(),
+        run_hashes: 0,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        status,
     };
 
     (ctx, handle)
 }
 
 impl Handle {
+    /// Stop the miner thread, does nothing if it has already exited
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        let _ = self.control_chan.send(ControlSignal::Exit);
     }
 
-    pub fn start(&self, lambda: u64) {
+    pub fn start(&self, lambda: u64) -> Result<(), String> {
         self.control_chan
             .send(ControlSignal::Start(lambda))
-            .unwrap();
+            .map_err(|_| "miner has been stopped".to_string())
+    }
+
+    pub fn pause(&self) -> Result<(), String> {
+        self.control_chan
+            .send(ControlSignal::Pause)
+            .map_err(|_| "miner has been stopped".to_string())
+    }
+
+    /// Mine exactly `n` blocks on the current tip and return their hashes, paying the rewards
+    /// to `address` or the configured reward address. The miner has to be paused, and pausing it
+    /// again interrupts the generation.
+    pub fn generate(&self, n: usize, address: Option<H160>) -> Result<Vec<H256>, String> {
+        if n > MAX_GENERATE {
+            return Err(format!("at most {} blocks can be generated at once", MAX_GENERATE));
+        }
+        let (reply, result) = bounded(1);
+        self.control_chan
+            .send(ControlSignal::Generate(n, address, reply))
+            .map_err(|_| "miner has been stopped".to_string())?;
+        result.recv().map_err(|_| "miner has been stopped".to_string())?
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
     }
 
 }
 
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        handle
     }
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
@@ -116,57 +217,76 @@ impl Context {
             ControlSignal::Start(i) => {
                 info!("Miner starting in continuous mode with lambda {}", i);
                 self.operating_state = OperatingState::Run(i);
+                self.run_since = Instant::now();
+                self.run_hashes = 0;
+            }
+            ControlSignal::Pause => {
+                info!("Miner paused");
+                self.generate_interrupted = true;
+                self.operating_state = OperatingState::Paused;
+            }
+            ControlSignal::Generate(n, address, reply) => {
+                let _ = reply.send(self.generate(n, address));
+                return;
+            }
+        }
+        let mut status = self.status.lock().unwrap();
+        match self.operating_state {
+            OperatingState::Paused => {
+                status.state = MinerState::Paused;
+                status.template = None;
+            }
+            OperatingState::Run(i) => {
+                status.state = MinerState::Running;
+                status.lambda = i;
+                status.hashrate = 0.0;
+            }
+            OperatingState::ShutDown => {
+                status.state = MinerState::Shutdown;
+                status.template = None;
             }
         }
     }
 
-    fn miner_loop(&mut self) {
-        use hex_literal::hex;
-
-        let mut num_blocks = 0;
-        let key = key_pair::random();
-        let public_key = key.public_key();
-        let byte_pbkey = public_key.as_ref();
-        let address = H160::hash(&byte_pbkey);
-        println!("self address: {:?}",address);
-        let mut address_vec = vec![address];
-        self.blkchain.lock().unwrap().address_list.push(address);
-        self.server.broadcast(Message::Address(address_vec));
-        println!("self address: {:?}", self.blkchain.lock().unwrap().address_list);
-        self.key = key;
-        self.self_address = address;
-
-        let mut blkchain = self.blkchain.lock().unwrap();
-        let mut st = HashMap::new();
-        let hash_val = blkchain.tip;
-        for addr in blkchain.address_list.clone(){
-            let mut opt = Vec::new();
-            let mut ipt = Vec::new();
-            opt.push(Output{
-                val:10,
-                address:addr,
-            });
-            let mut tx = Transaction{
-                id:0,
-                tx_in:ipt,
-                tx_out:opt,
-            };
-            info!("ico -> {:?}", tx);
-            st.insert(tx.hash(), Output{
-                val:10,
-                address:addr,
-            });
+    /// Handle pending control signals without blocking, returns whether the miner is still running
+    fn poll_control(&mut self) -> bool {
+        loop {
+            match self.control_chan.try_recv() {
+                Ok(signal) => self.handle_control_signal(signal),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
+            }
         }
-        let mut blkstate = State{
-            map: st
-        };
+        if let OperatingState::Run(_) = self.operating_state {
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Handle pending control signals, returns whether a nonce search should go on
+    fn keep_mining(&mut self) -> bool {
+        let running = self.poll_control();
+        match self.operating_state {
+            OperatingState::ShutDown => false,
+            _ if self.generating => !self.generate_interrupted,
+            _ => running,
+        }
+    }
+
+    /// Record the hashes tried since the last update in the shared status
+    fn record_hashes(&mut self, hashes: u64) {
+        self.run_hashes += hashes;
+        let elapsed = self.run_since.elapsed().as_secs_f64();
+        let mut status = self.status.lock().unwrap();
+        status.hashes += hashes;
+        if elapsed > 0.0 {
+            status.hashrate = self.run_hashes as f64 / elapsed;
+        }
+    }
 
-        let start = Instant::now();
-        blkchain.block_state.insert(hash_val.clone(), blkstate.clone() );
-        blkchain.current_state = blkstate.clone();
-        drop(blkchain);
+    fn miner_loop(&mut self) {
         loop {
-            // println!("key: {:?}, self address: {:?}", self.key, self.self_address);
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
@@ -177,189 +297,212 @@ impl Context {
                 OperatingState::ShutDown => {
                     return;
                 }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        self.handle_control_signal(signal);
-                    }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
-                },
+                _ => {
+                    self.poll_control();
+                }
             }
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
 
-            // TODO: actual mining
+            if let OperatingState::Run(_) = self.operating_state {
+                self.mine_block(None);
+            }
 
             if let OperatingState::Run(i) = self.operating_state {
-                if i== 1 {
-                    let mut chain = self.blkchain.lock().unwrap();
-                    info!("{:?}", chain.tip);
-                    drop(chain);
-                    println!("address list: {:?}", self.blkchain.lock().unwrap().address_list);
-                    let mut chain = self.blkchain.lock().unwrap();
-                    let hash_val = chain.tip;
-                    let dif = chain.key_val.get(&hash_val).unwrap().head.clone().difficulty;
-                    // println!("{:?}", chain.current_state);
-                    drop(chain);
-                    // info!("{:?},{:?}",hash_val,self.blkchain.lock().unwrap().block_state );
-                    // let mut pool = self.mem_pool.lock().unwrap().pool;
-                    let mut rng = rand::thread_rng();
-                    let num_transactions = rng.gen_range(2, 5);
-                    info!("num of transacation: {:?}", num_transactions);
-                    for i in 0..num_transactions {
-                        let random_transaction = self.gen_rand_signed(&hash_val);
-                        // println!("{:?}", random_transaction);
-                        self.blkchain.lock().unwrap().update_state(&random_transaction.clone().tx);
-                        // println!("{:?}", cur_state);
-                        // self.blkchain.lock().unwrap().current_state = cur_state;
-                        self.mem_pool.lock().unwrap().pool.insert(random_transaction.hash(), random_transaction.clone());
-                        self.server.broadcast(Message::NewTransactionHashes(vec![random_transaction.clone().hash()]));
-                    }
-                    // drop(pool);
-                    let mut chain = self.blkchain.lock().unwrap();
-                    let mut new= block::generate_rand_block(&hash_val);
-                    // new.body.data = data;
-                    new.index = chain.key_val.get(&hash_val).unwrap().index+1;
-                    // drop(data);
-                    drop(chain);
-                    loop
-                    {
-                        // info!("mempool size: {:?}", self.mem_pool.lock().unwrap().pool.capacity());
-                        let mut rng = rand::thread_rng();
-                        new.head.nonce = rng.gen::<u32>();
-                        let mut cnt = 0;
-                        let mut data = Vec::<SignedTrans>::new();
-                        let mut pool = self.mem_pool.lock().unwrap().clone().pool;
-                        for (hash,val) in pool.clone(){
-                            if cnt==3{
-                                break;
-                            }
-                            data.push(val);
-                            self.mem_pool.lock().unwrap().pool.remove(&hash);
-                            cnt += 1;
-                        }
-                        drop(pool);
-                        new.body.data = data;
-
-                        if new.hash() <= dif {
-                            let mut chain = self.blkchain.lock().unwrap();
-                            chain.insert(&new);
-                            let current = chain.clone().current_state;
-                            chain.block_state.insert(new.hash(), current);
-                            drop(chain);
-                            let mut block_vec = Vec::new();
-                            block_vec.push(new.hash());
-                            let msg = Message::NewBlockHashes(block_vec);
-                            self.server.broadcast(msg);
-                            num_blocks += 1;
-                            info!("num of blocks {}", num_blocks);
-                            break;
-                        }
-                    }
-                    let time = start.elapsed().as_secs();
-                    if time >= 30{
-                        let chain = self.blkchain.lock().unwrap();
-                        let chain_blocks = chain.get_num();
-                        println!("length of the blockchain {}", chain_blocks);
-                        println!("Time elapsed in mining {} blocks is: {:?}s", num_blocks, time);
-                        break;
-                        info!("time out");
-                        drop(chain);
-                    }
+                if i != 0 {
+                    let interval = time::Duration::from_micros(i as u64);
+                    thread::sleep(interval);
                 }
             }
         }
     }
 
-    fn gen_rand_signed(&self, pre_hash:&H256) -> SignedTrans{
-        let id = 1;
-        let mut in_val:u8 = 1;
-        let out_val:u8 = 1;
-        // info!("1");
-        let (tx_in,in_val) = self.get_input(&out_val, pre_hash);
-        // info!("2");
-        let address = self.get_address();
-        // info!("3");
-        let tx_out = self.get_output(&in_val,&out_val,&address);
-        // info!("4");
-        let tx = Transaction{id, tx_in, tx_out};
-        let signature = sign(&tx, &self.key);
-        let public_key = self.key.public_key().as_ref().to_vec();
-        SignedTrans{
-            tx,
-            signature,
-            public_key,
+    /// Mine one block on the current tip with transactions from the mempool, returns its hash.
+    /// Returns None if the miner was paused or stopped first.
+    fn mine_block(&mut self, reward_address: Option<H160>) -> Option<H256> {
+        let chain = locks::read_chain(&self.blkchain);
+        let hash_val = chain.tip;
+        let index = chain.key_val[&hash_val].index + 1;
+        let dif = chain.next_difficulty(&hash_val);
+        let min_time_stamp = chain.median_time_past(&hash_val) + 1;
+        let reward = chain.params.block_reward;
+        let max_block_size = chain.params.max_block_size;
+        drop(chain);
+
+        let mut new= block::generate_rand_block(&hash_val, dif);
+        new.index = index;
+        // blocks mined in quick succession could otherwise share a time stamp
+        new.head.time_stamp = new.head.time_stamp.max(min_time_stamp);
+        // the transactions are picked once, every thread searches nonces for the same block
+        let reward_address = reward_address.or(self.reward_address).unwrap_or(self.self_address);
+        let mut data = vec![transaction::coin_base(&reward_address, reward, &hash_val)];
+        let mut pool = locks::lock_pool(&self.mem_pool);
+        data.extend(pool.pool.values().take(max_block_size).cloned());
+        for tx in &data[1..] {
+            pool.remove(tx);
+        }
+        drop(pool);
+        new.head.mkl_root = MerkleTree::new(&data).root();
+        new.body.data = data;
+        self.status.lock().unwrap().template = Some(Template {
+            parent: hash_val.to_string(),
+            index: new.index,
+            difficulty: dif.to_string(),
+            num_transactions: new.transactions().len(),
+        });
+
+        match self.search_nonce(&new, &dif) {
+            Some(nonce) => {
+                new.head.nonce = nonce;
+                let hash = new.hash();
+                let mut chain = locks::write_chain(&self.blkchain);
+                chain.insert(&new);
+                drop(chain);
+                self.server.broadcast(Message::NewBlockHashes(vec![hash]));
+                let mut status = self.status.lock().unwrap();
+                status.blocks_mined += 1;
+                info!("num of blocks {}", status.blocks_mined);
+                Some(hash)
+            }
+            None => {
+                // paused or stopped, the transactions go back for the next block
+                let mut pool = locks::lock_pool(&self.mem_pool);
+                for tx in new.transactions() {
+                    pool.add(tx);
+                }
+                None
+            }
         }
     }
 
-    fn get_input(&self, out_val:&u8, hash: &H256) -> (Vec<Input>, u8){
-        // info!("11");
-        let mut chain = self.blkchain.lock().unwrap().clone();
-        // info!("11");
-
-        let pre_st = chain.current_state;
-        // info!("11");
-
-        let map = pre_st.map;
-        //TODO
-        let mut input_vec = Vec::new();
-        // info!("11");
-
-        let mut current_val:u8 = 0;
-        for (tx_hash, output) in map{
-            if current_val < out_val.clone() {
-                let t = Input{
-                    val: output.get_val(),
-                    previous_hash: tx_hash,
-                };
-                current_val += output.get_val();
-                input_vec.push(t);
-            }else{
-                // info!("112");
-
-                break;
+    /// Mine `n` blocks in a row, without generating transactions
+    fn generate(&mut self, n: usize, address: Option<H160>) -> Result<Vec<H256>, String> {
+        if let OperatingState::Run(_) = self.operating_state {
+            return Err("miner is running, pause it first".to_string());
+        }
+        if self.generating {
+            return Err("already generating blocks".to_string());
+        }
+        info!("Generating {} blocks", n);
+        self.generating = true;
+        self.generate_interrupted = false;
+        let mut hashes = Vec::new();
+        while hashes.len() < n {
+            match self.mine_block(address) {
+                Some(hash) => hashes.push(hash),
+                None => break,
             }
         }
-        return (input_vec, current_val);
+        self.generating = false;
+        self.status.lock().unwrap().template = None;
+        if hashes.len() < n {
+            return Err(format!("miner stopped after {} of {} blocks", hashes.len(), n));
+        }
+        Ok(hashes)
     }
 
-    fn get_output(&self, in_val:&u8, out_val: &u8, address:&H160) -> Vec<Output>{
-        let val = in_val.clone();
-        let out = out_val.clone();
-        return if val == out {
-            let o = Output {
-                val,
-                address: address.clone(),
-            };
-            vec![o]
-        } else if val > out {
-            let mut tx_out = Vec::new();
-            let o = Output {
-                val: out,
-                address: address.clone()
-            };
-            tx_out.push(o);
-            let change = Output {
-                val: val - out,
-                address: self.self_address,
+    /// Search for a nonce that brings the block's hash under the target on all mining threads.
+    /// Returns None if the miner was paused or stopped first.
+    fn search_nonce(&mut self, template: &Block, target: &H256) -> Option<u32> {
+        let (found_sender, found) = bounded(self.threads);
+        let done = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let threads = self.threads;
+        let nonce = crossbeam::scope(|scope| {
+            for _ in 0..threads {
+                let found_sender = found_sender.clone();
+                let (done, hashes) = (&done, &hashes);
+                scope.spawn(move |_| {
+                    let mut block = template.clone();
+                    let mut rng = rand::thread_rng();
+                    while !done.load(Ordering::Relaxed) {
+                        for _ in 0..HASH_REPORT_INTERVAL {
+                            block.head.nonce = rng.gen::<u32>();
+                            if block.hash() <= *target {
+                                let _ = found_sender.try_send(block.head.nonce);
+                                break;
+                            }
+                        }
+                        hashes.fetch_add(HASH_REPORT_INTERVAL, Ordering::Relaxed);
+                    }
+                });
+            }
+            // this thread keeps the status up to date and reacts to pause/exit while the others hash
+            let nonce = loop {
+                match found.recv_timeout(STATUS_INTERVAL) {
+                    Ok(nonce) => break Some(nonce),
+                    Err(_) => {
+                        self.record_hashes(hashes.swap(0, Ordering::Relaxed));
+                        if !self.keep_mining() {
+                            break None;
+                        }
+                    }
+                }
             };
-            tx_out.push(change);
-            tx_out
-        } else {
-            // println!(" overspend");
-            Vec::new()
-        }
+            done.store(true, Ordering::Relaxed);
+            nonce
+        })
+        .unwrap();
+        self.record_hashes(hashes.swap(0, Ordering::Relaxed));
+        nonce
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::server;
+    use crate::params::ChainParams;
+    use crate::transaction::gen_rand_signtx;
+
+    fn start_miner(params: ChainParams) -> (server::Context, Handle, Arc<RwLock<Blockchain>>, Arc<Mutex<Mempool>>) {
+        let (server_ctx, server) = server::tests::unstarted();
+        let blkchain = Arc::new(RwLock::new(Blockchain::new(params)));
+        let mem_pool = Arc::new(Mutex::new(Mempool::new()));
+        let (ctx, handle) = new(&server, &blkchain, &mem_pool, H160::default(), 1, None);
+        ctx.start();
+        (server_ctx, handle, blkchain, mem_pool)
     }
 
-    fn get_address(&self) -> H160{
-        let address_list = self.blkchain.lock().unwrap().clone().address_list;
-        if address_list.len() == 1 {
-            info!("Only one address in the list");
-            return address_list[0];
+    #[test]
+    fn generate_blocks() {
+        let (_server, miner, blkchain, _) = start_miner(ChainParams::regtest());
+        let length = locks::read_chain(&blkchain).length;
+        let hashes = miner.generate(5, None).unwrap();
+        assert_eq!(hashes.len(), 5);
+        let chain = locks::read_chain(&blkchain);
+        assert_eq!(chain.length, length + 5);
+        assert_eq!(chain.tip(), hashes[4]);
+        drop(chain);
+
+        assert!(miner.generate(MAX_GENERATE + 1, None).is_err());
+        miner.start(0).unwrap();
+        assert!(miner.generate(1, None).unwrap_err().contains("running"));
+        miner.exit();
+    }
+
+    #[test]
+    fn pause_interrupts_generate() {
+        // no hash meets this target, so the miner searches until it is paused
+        let params = ChainParams {
+            genesis_difficulty: [0; 32].into(),
+            ..ChainParams::regtest()
+        };
+        let (_server, miner, blkchain, mem_pool) = start_miner(params);
+        let tx = gen_rand_signtx();
+        locks::lock_pool(&mem_pool).add(&tx);
+
+        let generating = miner.clone();
+        let result = thread::spawn(move || generating.generate(3, None));
+        while miner.status().template.is_none() {
+            thread::sleep(time::Duration::from_millis(10));
         }
-        let mut rng = rand::thread_rng();
-        let index = rng.gen_range(1, address_list.len());
-        return address_list[index];
+        assert!(locks::lock_pool(&mem_pool).pool.is_empty());
+        miner.pause().unwrap();
+        assert!(result.join().unwrap().is_err());
+        assert!(locks::lock_pool(&mem_pool).pool.contains_key(&tx.hash()));
+        assert_eq!(locks::read_chain(&blkchain).length, 0);
+        miner.exit();
     }
 }
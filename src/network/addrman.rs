@@ -0,0 +1,242 @@
+use super::message::NetAddress;
+use log::debug;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most addresses remembered, the least recently seen are forgotten first
+const MAX_ADDRESSES: usize = 4096;
+/// Failed attempts after which an address that never worked is forgotten
+const MAX_FAILURES: u32 = 10;
+/// Base wait in seconds before retrying an address, multiplied by the failed attempts
+const RETRY_INTERVAL: u64 = 60;
+
+/// What we know about one peer address.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddrInfo {
+    pub addr: SocketAddr,
+    /// When the address was last announced or connected to, unix seconds
+    pub last_seen: u64,
+    pub last_attempt: u64,
+    /// When we last completed a handshake with it, 0 if never
+    pub last_success: u64,
+    /// Failed attempts since the last success
+    pub attempts: u32,
+}
+
+impl AddrInfo {
+    pub fn is_tried(&self) -> bool {
+        self.last_success > 0
+    }
+}
+
+/// Known peer addresses, learned from `Addr` messages and our own connections.
+pub struct AddrManager {
+    addrs: HashMap<SocketAddr, AddrInfo>,
+    /// Our own listening address, which we never want to dial
+    local: Option<SocketAddr>,
+    /// File the addresses are persisted to
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl AddrManager {
+    /// Create an address manager, loading previously saved addresses from `path` if it exists
+    pub fn new(local: Option<SocketAddr>, path: Option<PathBuf>) -> std::io::Result<Self> {
+        let mut addrman = AddrManager {
+            addrs: HashMap::new(),
+            local,
+            path,
+            dirty: false,
+        };
+        if let Some(path) = &addrman.path {
+            if path.exists() {
+                let content = std::fs::read_to_string(path)?;
+                let saved: Vec<AddrInfo> = serde_json::from_str(&content)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                debug!("Loaded {} peer addresses from {}", saved.len(), path.display());
+                addrman.addrs = saved.into_iter().map(|a| (a.addr, a)).collect();
+            }
+        }
+        Ok(addrman)
+    }
+
+    /// Write the addresses to disk if they changed since the last save
+    pub fn save(&mut self) -> std::io::Result<()> {
+        let path = match &self.path {
+            Some(p) if self.dirty => p,
+            _ => return Ok(()),
+        };
+        let saved: Vec<&AddrInfo> = self.addrs.values().collect();
+        let content = serde_json::to_string_pretty(&saved)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        // write to a temporary file first so a crash never leaves a truncated file behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    /// Learn about an address, returns true if it was not known before
+    pub fn add(&mut self, address: &NetAddress) -> bool {
+        let addr = address.addr;
+        if addr.ip().is_unspecified() || addr.port() == 0 || Some(addr) == self.local {
+            return false;
+        }
+        // don't trust timestamps from the future
+        let timestamp = address.timestamp.min(now());
+        self.dirty = true;
+        if let Some(info) = self.addrs.get_mut(&addr) {
+            info.last_seen = info.last_seen.max(timestamp);
+            return false;
+        }
+        if self.addrs.len() >= MAX_ADDRESSES {
+            self.evict();
+        }
+        self.addrs.insert(addr, AddrInfo {
+            addr,
+            last_seen: timestamp,
+            last_attempt: 0,
+            last_success: 0,
+            attempts: 0,
+        });
+        true
+    }
+
+    /// Record that we are about to dial an address
+    pub fn attempt(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.last_attempt = now();
+            info.attempts += 1;
+            self.dirty = true;
+            if !info.is_tried() && info.attempts >= MAX_FAILURES {
+                debug!("Forgetting peer address {} after {} failed attempts", addr, info.attempts);
+                self.addrs.remove(addr);
+            }
+        }
+    }
+
+    /// Record a successful handshake with an address we dialed
+    pub fn good(&mut self, addr: &SocketAddr) {
+        let now = now();
+        let info = self.addrs.entry(*addr).or_insert(AddrInfo {
+            addr: *addr,
+            last_seen: now,
+            last_attempt: now,
+            last_success: 0,
+            attempts: 0,
+        });
+        info.last_seen = now;
+        info.last_success = now;
+        info.attempts = 0;
+        self.dirty = true;
+    }
+
+    /// Pick an address to dial that is not in `exclude` and not waiting for a retry.
+    /// Addresses that worked before are preferred.
+    pub fn select(&self, exclude: &HashSet<SocketAddr>) -> Option<SocketAddr> {
+        let now = now();
+        let candidates: Vec<&AddrInfo> = self
+            .addrs
+            .values()
+            .filter(|a| !exclude.contains(&a.addr))
+            .filter(|a| a.attempts == 0 || now >= a.last_attempt + RETRY_INTERVAL * a.attempts as u64)
+            .collect();
+        let tried: Vec<&AddrInfo> = candidates.iter().cloned().filter(|a| a.is_tried()).collect();
+        let mut rng = rand::thread_rng();
+        if !tried.is_empty() {
+            tried.choose(&mut rng).map(|a| a.addr)
+        } else {
+            candidates.choose(&mut rng).map(|a| a.addr)
+        }
+    }
+
+    /// The most recently seen addresses, to answer a `GetAddr`
+    pub fn addresses(&self, max: usize) -> Vec<NetAddress> {
+        let mut infos: Vec<&AddrInfo> = self.addrs.values().collect();
+        infos.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        infos
+            .into_iter()
+            .take(max)
+            .map(|a| NetAddress {
+                addr: a.addr,
+                timestamp: a.last_seen,
+            })
+            .collect()
+    }
+
+    /// Forget the least recently seen address, sparing those that worked before if possible
+    fn evict(&mut self) {
+        let victim = self
+            .addrs
+            .values()
+            .min_by_key(|a| (a.is_tried(), a.last_seen))
+            .map(|a| a.addr);
+        if let Some(addr) = victim {
+            self.addrs.remove(&addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> NetAddress {
+        NetAddress {
+            addr: format!("10.0.0.1:{}", port).parse().unwrap(),
+            timestamp: now(),
+        }
+    }
+
+    #[test]
+    fn add_and_select() {
+        let local = address(1).addr;
+        let mut addrman = AddrManager::new(Some(local), None).unwrap();
+        assert!(!addrman.add(&address(1)));
+        assert!(addrman.add(&address(2)));
+        assert!(!addrman.add(&address(2)));
+        assert!(addrman.add(&address(3)));
+        addrman.good(&address(3).addr);
+        // tried addresses are preferred
+        assert_eq!(addrman.select(&HashSet::new()), Some(address(3).addr));
+        let mut exclude = HashSet::new();
+        exclude.insert(address(3).addr);
+        assert_eq!(addrman.select(&exclude), Some(address(2).addr));
+        // a failed address waits before being retried
+        addrman.attempt(&address(2).addr);
+        assert_eq!(addrman.select(&exclude), None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("addrman-test-{}.json", rand::random::<u64>()));
+        let mut addrman = AddrManager::new(None, Some(path.clone())).unwrap();
+        addrman.add(&address(2));
+        addrman.good(&address(3).addr);
+        addrman.save().unwrap();
+        let loaded = AddrManager::new(None, Some(path.clone())).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.addrs[&address(3).addr].is_tried());
+        std::fs::remove_file(path).unwrap();
+    }
+}
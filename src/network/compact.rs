@@ -0,0 +1,167 @@
+use crate::block::{Block, Content, Header};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::SignedTrans;
+use ring::digest;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+/// Short transaction ids are this many bytes of a salted hash
+const SHORT_ID_BYTES: usize = 6;
+
+/// Identify a transaction within a block, salted with the block hash so collisions
+/// can't be crafted ahead of time for every block.
+pub fn short_id(block_hash: &H256, tx_hash: &H256) -> u64 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(block_hash.as_ref());
+    ctx.update(tx_hash.as_ref());
+    let digest = ctx.finish();
+    let mut id = [0u8; 8];
+    id[..SHORT_ID_BYTES].copy_from_slice(&digest.as_ref()[..SHORT_ID_BYTES]);
+    u64::from_le_bytes(id)
+}
+
+/// A block with its transactions replaced by short ids, which peers fill in from their mempool.
+/// The coinbase is never in a mempool, so it is sent in full.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub head: Header,
+    pub index: usize,
+    pub coinbase: Option<SignedTrans>,
+    pub short_ids: Vec<u64>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let hash = block.hash();
+        CompactBlock {
+            head: block.head.clone(),
+            index: block.index,
+            coinbase: block.coinbase().cloned(),
+            short_ids: block.transactions().iter().map(|tx| short_id(&hash, &tx.hash())).collect(),
+        }
+    }
+
+    pub fn hash(&self) -> H256 {
+        // the block hash only covers the header
+        Block {
+            head: self.head.clone(),
+            body: Content { data: vec![] },
+            index: self.index,
+        }
+        .hash()
+    }
+}
+
+/// A compact block being filled in with transactions, at their positions in the block.
+pub struct PartialBlock {
+    compact: CompactBlock,
+    txs: Vec<Option<SignedTrans>>,
+}
+
+/// Outcome of filling in a partial block.
+pub enum Reconstruction {
+    Complete(Block),
+    /// Positions of the transactions we still need
+    Missing(Vec<u32>),
+    /// The transactions don't match the merkle root, most likely a short id collision
+    Mismatch,
+}
+
+impl PartialBlock {
+    /// Fill in what we can from the transactions in our mempool
+    pub fn new(compact: CompactBlock, pool: &HashMap<H256, SignedTrans>) -> Self {
+        let hash = compact.hash();
+        let by_short_id: HashMap<u64, &SignedTrans> =
+            pool.iter().map(|(tx_hash, tx)| (short_id(&hash, tx_hash), tx)).collect();
+        let txs = compact
+            .coinbase
+            .iter()
+            .map(|coinbase| Some(coinbase.clone()))
+            .chain(compact.short_ids.iter().map(|id| by_short_id.get(id).map(|tx| (*tx).clone())))
+            .collect();
+        PartialBlock { compact, txs }
+    }
+
+    /// Add transactions a peer sent for the given positions; unknown positions are ignored
+    pub fn fill(&mut self, indexes: &[u32], txs: Vec<SignedTrans>) {
+        for (index, tx) in indexes.iter().zip(txs) {
+            if let Some(slot) = self.txs.get_mut(*index as usize) {
+                *slot = Some(tx);
+            }
+        }
+    }
+
+    pub fn missing(&self) -> Vec<u32> {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    pub fn reconstruct(&self) -> Reconstruction {
+        let missing = self.missing();
+        if !missing.is_empty() {
+            return Reconstruction::Missing(missing);
+        }
+        let data: Vec<SignedTrans> = self.txs.iter().map(|tx| tx.clone().unwrap()).collect();
+        if MerkleTree::new(&data).root() != self.compact.head.mkl_root {
+            return Reconstruction::Mismatch;
+        }
+        Reconstruction::Complete(Block {
+            head: self.compact.head.clone(),
+            body: Content { data },
+            index: self.compact.index,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::crypto::hash::generate_rand_hash160;
+    use crate::transaction::{coin_base, gen_rand_signtx};
+
+    #[test]
+    fn reconstruct_from_mempool() {
+        let mut block = generate_random_block(&generate_random_hash());
+        block.body.data = vec![coin_base(&generate_rand_hash160(), 10, &block.head.block_parent)];
+        block.body.data.extend((0..4).map(|_| gen_rand_signtx()));
+        block.head.mkl_root = MerkleTree::new(&block.body.data).root();
+        let compact = CompactBlock::new(&block);
+        assert_eq!(compact.hash(), block.hash());
+
+        // we have all but the second signed transaction, the coinbase comes with the compact block
+        let pool: HashMap<H256, SignedTrans> = block
+            .body
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0 && *i != 2)
+            .map(|(_, tx)| (tx.hash(), tx.clone()))
+            .collect();
+        let mut partial = PartialBlock::new(compact, &pool);
+        match partial.reconstruct() {
+            Reconstruction::Missing(missing) => assert_eq!(missing, vec![2]),
+            _ => panic!("expected a missing transaction"),
+        }
+        partial.fill(&[2], vec![block.body.data[2].clone()]);
+        match partial.reconstruct() {
+            Reconstruction::Complete(rebuilt) => {
+                assert_eq!(rebuilt.hash(), block.hash());
+                assert_eq!(rebuilt.body.data.len(), 5);
+            }
+            _ => panic!("expected a complete block"),
+        }
+        // a wrong transaction in place of the right one is caught by the merkle root
+        partial.fill(&[2], vec![gen_rand_signtx()]);
+        assert!(match partial.reconstruct() {
+            Reconstruction::Mismatch => true,
+            _ => false,
+        });
+    }
+}
@@ -0,0 +1,199 @@
+use super::transport::{Identity, KeyExchange};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Version of the P2P protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 4;
+/// Oldest protocol version we can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+/// Service flag: the node stores the chain and serves blocks
+pub const NODE_NETWORK: u64 = 1;
+
+/// The chain a node participates in, identified on the wire by a magic value.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network {
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network {}, expected mainnet, testnet or regtest", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The first message each side sends on a new connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub version: u32,
+    pub magic: [u8; 4],
+    pub services: u64,
+    pub best_height: u64,
+    /// Random per-node value, used to detect connecting to ourselves
+    pub nonce: u64,
+    /// Key for the encrypted transport, empty if we don't offer it
+    pub ephemeral_key: Vec<u8>,
+    /// Identity key we will prove we hold once encrypted, empty if we have none
+    pub identity_key: Vec<u8>,
+}
+
+/// What this node announces about itself in the handshake.
+pub struct Local {
+    pub network: Network,
+    pub services: u64,
+    pub nonce: u64,
+    /// Reports the height of our best chain when a handshake starts
+    pub best_height: Box<dyn Fn() -> u64 + Send>,
+    /// Whether we offer the encrypted transport, used when both sides offer it
+    pub encrypt: bool,
+    pub identity: Option<Identity>,
+    /// Identity keys peers at these IP addresses must prove they hold. Pinned by IP rather than
+    /// socket address, as incoming connections come from ephemeral ports.
+    pub trusted: HashMap<IpAddr, Vec<u8>>,
+}
+
+impl Local {
+    pub fn new(network: Network, best_height: Box<dyn Fn() -> u64 + Send>) -> Self {
+        Local {
+            network,
+            services: NODE_NETWORK,
+            nonce: rand::random(),
+            best_height,
+            encrypt: false,
+            identity: None,
+            trusted: HashMap::new(),
+        }
+    }
+
+    pub fn version(&self) -> Version {
+        Version {
+            version: PROTOCOL_VERSION,
+            magic: self.network.magic(),
+            services: self.services,
+            best_height: (self.best_height)(),
+            nonce: self.nonce,
+            ephemeral_key: vec![],
+            identity_key: self.identity.as_ref().map(|i| i.public_key()).unwrap_or_default(),
+        }
+    }
+
+    /// Decide whether we can talk to a peer that sent us this version
+    pub fn check(&self, remote: &Version) -> Result<(), String> {
+        if remote.magic != self.network.magic() {
+            return Err(format!("peer is on a different network, magic {:02x?}", remote.magic));
+        }
+        if remote.version < MIN_PROTOCOL_VERSION {
+            return Err(format!("peer protocol version {} is too old", remote.version));
+        }
+        if remote.nonce == self.nonce {
+            return Err("connected to ourselves".to_string());
+        }
+        Ok(())
+    }
+
+    /// Decide whether a peer finishing its handshake proved the identity we expect from it
+    pub fn check_identity(&self, addr: &SocketAddr, state: &State) -> Result<(), String> {
+        let announced = state.remote.as_ref().map_or(false, |v| !v.identity_key.is_empty());
+        if state.encrypted && announced && state.identity.is_none() {
+            return Err("peer did not prove its identity".to_string());
+        }
+        match self.trusted.get(&addr.ip()) {
+            Some(key) if state.identity.as_ref() != Some(key) => {
+                Err("peer did not prove the trusted identity".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Progress of the handshake with one peer.
+#[derive(Default)]
+pub struct State {
+    /// The version the peer announced
+    pub remote: Option<Version>,
+    pub verack_received: bool,
+    /// Our side of the key agreement, until the peer's version arrives
+    pub key_exchange: Option<KeyExchange>,
+    /// Our ephemeral public key, which the peer's identity proof covers
+    pub local_ephemeral: Vec<u8>,
+    pub encrypted: bool,
+    /// Identity key the peer proved it holds
+    pub identity: Option<Vec<u8>>,
+}
+
+impl State {
+    pub fn is_complete(&self) -> bool {
+        self.remote.is_some() && self.verack_received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_version() {
+        let local = Local::new(Network::Testnet, Box::new(|| 0));
+        let mut remote = Local::new(Network::Testnet, Box::new(|| 5)).version();
+        assert!(local.check(&remote).is_ok());
+        remote.magic = Network::Mainnet.magic();
+        assert!(local.check(&remote).is_err());
+        assert!(local.check(&local.version()).is_err());
+    }
+
+    #[test]
+    fn check_identity() {
+        let addr: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let mut local = Local::new(Network::Testnet, Box::new(|| 0));
+        let mut remote = Local::new(Network::Testnet, Box::new(|| 0)).version();
+        remote.identity_key = vec![1; 32];
+        let mut state = State {
+            remote: Some(remote),
+            encrypted: true,
+            ..State::default()
+        };
+        assert!(local.check_identity(&addr, &state).is_err());
+        state.identity = Some(vec![1; 32]);
+        assert!(local.check_identity(&addr, &state).is_ok());
+        local.trusted.insert(addr.ip(), vec![2; 32]);
+        assert!(local.check_identity(&addr, &state).is_err());
+        // the pin covers any port, like an incoming connection from an ephemeral one
+        let incoming: SocketAddr = "10.0.0.1:53124".parse().unwrap();
+        assert!(local.check_identity(&incoming, &state).is_err());
+        state.identity = Some(vec![2; 32]);
+        assert!(local.check_identity(&addr, &state).is_ok());
+        assert!(local.check_identity(&incoming, &state).is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::crypto::hash::H256;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a peer has to answer a request before we ask another one
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most peers remembered per item as alternatives to ask
+const MAX_ANNOUNCERS: usize = 8;
+/// How long after a request is completed or given up on the peers asked may still answer it
+const LATE_ANSWER_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Block,
+    Transaction,
+}
+
+/// How an item received from a peer relates to our requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Answer {
+    /// We are waiting on the peer for it
+    Requested,
+    /// We asked the peer, but got the item elsewhere or gave up on it meanwhile
+    Late,
+    /// We never asked the peer for it
+    Unrequested,
+}
+
+struct Request {
+    kind: Kind,
+    /// When the last peer in `asked` was asked
+    sent: Instant,
+    /// Peers we have asked so far, the one we are waiting on last
+    asked: Vec<usize>,
+    /// Peers that announced the item and could be asked next
+    announcers: Vec<usize>,
+}
+
+/// Blocks and transactions requested from peers and not received yet, one request per item.
+#[derive(Default)]
+pub struct Inflight {
+    requests: HashMap<H256, Request>,
+    /// Items no longer requested, with when that happened and the peers that may still answer
+    finished: HashMap<H256, (Instant, Vec<usize>)>,
+}
+
+impl Inflight {
+    pub fn new() -> Self {
+        Inflight::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.requests.contains_key(hash)
+    }
+
+    /// Whether we asked this peer for the item, without marking it as received
+    pub fn answer(&self, hash: &H256, peer: usize) -> Answer {
+        if let Some(request) = self.requests.get(hash) {
+            if request.asked.contains(&peer) {
+                return Answer::Requested;
+            }
+        }
+        match self.finished.get(hash) {
+            Some((_, asked)) if asked.contains(&peer) => Answer::Late,
+            _ => Answer::Unrequested,
+        }
+    }
+
+    /// Note that a peer announced an item we don't have.
+    /// Returns true if the item should be requested from this peer now,
+    /// false if it is already being requested from someone else.
+    pub fn announce(&mut self, kind: Kind, hash: H256, peer: usize) -> bool {
+        match self.requests.get_mut(&hash) {
+            Some(request) => {
+                if !request.asked.contains(&peer)
+                    && !request.announcers.contains(&peer)
+                    && request.announcers.len() < MAX_ANNOUNCERS
+                {
+                    request.announcers.push(peer);
+                }
+                false
+            }
+            None => {
+                self.requests.insert(hash, Request {
+                    kind,
+                    sent: Instant::now(),
+                    asked: vec![peer],
+                    announcers: vec![],
+                });
+                true
+            }
+        }
+    }
+
+    /// Note that an item arrived from a peer. The other peers asked for it may still answer late,
+    /// each of them once.
+    pub fn received(&mut self, hash: &H256, peer: usize) -> Answer {
+        let answer = self.answer(hash, peer);
+        match answer {
+            Answer::Requested => {
+                let mut request = self.requests.remove(hash).unwrap();
+                request.asked.retain(|p| *p != peer);
+                self.finish(*hash, request.asked, Instant::now());
+            }
+            Answer::Late => {
+                if let Some((_, asked)) = self.finished.get_mut(hash) {
+                    asked.retain(|p| *p != peer);
+                }
+            }
+            Answer::Unrequested => {}
+        }
+        answer
+    }
+
+    fn finish(&mut self, hash: H256, asked: Vec<usize>, now: Instant) {
+        if !asked.is_empty() {
+            self.finished.insert(hash, (now, asked));
+        }
+    }
+
+    /// Move requests that timed out to the next peer that announced the item, giving up on
+    /// items nobody else announced. Returns the requests to send as (peer, kind, hash).
+    pub fn expire(&mut self, now: Instant) -> Vec<(usize, Kind, H256)> {
+        let mut retries = vec![];
+        let mut abandoned = vec![];
+        for (hash, request) in self.requests.iter_mut() {
+            if now.duration_since(request.sent) < REQUEST_TIMEOUT {
+                continue;
+            }
+            if request.announcers.is_empty() {
+                abandoned.push(*hash);
+                continue;
+            }
+            let next = request.announcers.remove(0);
+            request.sent = now;
+            request.asked.push(next);
+            retries.push((next, request.kind, *hash));
+        }
+        for hash in abandoned {
+            let request = self.requests.remove(&hash).unwrap();
+            self.finish(hash, request.asked, now);
+        }
+        self.finished.retain(|_, (finished, asked)| {
+            !asked.is_empty() && now.duration_since(*finished) < LATE_ANSWER_WINDOW
+        });
+        retries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn dedup_and_retry() {
+        let mut inflight = Inflight::new();
+        let hash = generate_random_hash();
+        assert!(inflight.announce(Kind::Block, hash, 1));
+        assert!(!inflight.announce(Kind::Block, hash, 2));
+        assert!(!inflight.announce(Kind::Block, hash, 2));
+        assert!(inflight.expire(Instant::now()).is_empty());
+        let later = Instant::now() + REQUEST_TIMEOUT;
+        assert_eq!(inflight.expire(later), vec![(2, Kind::Block, hash)]);
+        // nobody else to ask, the request is dropped after the next timeout
+        assert!(inflight.expire(later + REQUEST_TIMEOUT).is_empty());
+        assert!(inflight.is_empty());
+    }
+
+    #[test]
+    fn unrequested() {
+        let mut inflight = Inflight::new();
+        let hash = generate_random_hash();
+        assert_eq!(inflight.received(&hash, 1), Answer::Unrequested);
+        inflight.announce(Kind::Transaction, hash, 1);
+        assert_eq!(inflight.received(&hash, 2), Answer::Unrequested);
+        assert_eq!(inflight.received(&hash, 1), Answer::Requested);
+        assert_eq!(inflight.received(&hash, 1), Answer::Unrequested);
+    }
+
+    #[test]
+    fn late_answers() {
+        let mut inflight = Inflight::new();
+        let hash = generate_random_hash();
+        inflight.announce(Kind::Block, hash, 1);
+        inflight.announce(Kind::Block, hash, 2);
+        let later = Instant::now() + REQUEST_TIMEOUT;
+        inflight.expire(later);
+        // the retried peer answers first, the one that timed out may still answer once
+        assert_eq!(inflight.received(&hash, 2), Answer::Requested);
+        assert_eq!(inflight.received(&hash, 1), Answer::Late);
+        assert_eq!(inflight.received(&hash, 1), Answer::Unrequested);
+
+        // abandoned requests may be answered late too, until the window has passed
+        let hash = generate_random_hash();
+        inflight.announce(Kind::Transaction, hash, 3);
+        let later = later + REQUEST_TIMEOUT;
+        inflight.expire(later);
+        assert!(!inflight.contains(&hash));
+        assert_eq!(inflight.answer(&hash, 3), Answer::Late);
+        inflight.expire(later + LATE_ANSWER_WINDOW);
+        assert_eq!(inflight.answer(&hash, 3), Answer::Unrequested);
+    }
+}
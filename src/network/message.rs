@@ -1,7 +1,32 @@
 use serde::{Serialize, Deserialize};
+use ring::digest;
 use crate::crypto::hash::{H256, H160};
 use crate::block::Block;
 use crate::transaction::{Transaction, SignedTrans};
+use super::compact::CompactBlock;
+use super::handshake::Version;
+use super::queue::Lane;
+
+/// Largest encoded message we send or accept, in bytes
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+/// Most peer addresses sent in one `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+
+const COMMAND_LEN: usize = 16;
+const CHECKSUM_LEN: usize = 4;
+/// Bytes in front of every payload on the wire: command, flags and checksum
+pub const HEADER_LEN: usize = COMMAND_LEN + 1 + CHECKSUM_LEN;
+/// Payloads at least this large are compressed when that makes them smaller
+const COMPRESSION_THRESHOLD: usize = 1024;
+const FLAG_COMPRESSED: u8 = 1;
+
+/// A peer's listening address and when it was last known to be active.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetAddress {
+    pub addr: std::net::SocketAddr,
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -13,5 +38,167 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTrans>),
+    /// Wallet addresses taking part in the initial coin allocation
     Address(Vec<H160>),
+    Version(Version),
+    VerAck,
+    /// Proof the sender holds the identity key in its version, sent over the encrypted transport
+    Auth(Vec<u8>),
+    GetAddr,
+    Addr(Vec<NetAddress>),
+    /// Ask for blocks as `CompactBlock`s rather than in full
+    GetCompactBlocks(Vec<H256>),
+    CompactBlock(CompactBlock),
+    /// Ask for the transactions of a block at the given positions
+    GetBlockTransactions(H256, Vec<u32>),
+    /// Transactions of a block, along with their positions in it
+    BlockTransactions(H256, Vec<u32>, Vec<SignedTrans>),
+}
+
+/// Defines the command of each message and the worker queue lane it goes in, keeping the
+/// frame header names, `Message::command` and `Lane::of` in agreement
+macro_rules! commands {
+    ($($variant:pat => $command:expr, $lane:ident;)*) => {
+        /// Names of the messages, as written in frame headers, and their lanes
+        pub const COMMANDS: &[(&str, Lane)] = &[$(($command, Lane::$lane)),*];
+
+        impl Message {
+            pub fn command(&self) -> &'static str {
+                match self {
+                    $($variant => $command,)*
+                }
+            }
+        }
+    };
+}
+
+commands! {
+    Message::Ping(_) => "ping", Other;
+    Message::Pong(_) => "pong", Other;
+    Message::NewBlockHashes(_) => "newblocks", Block;
+    Message::GetBlocks(_) => "getblocks", Block;
+    Message::Blocks(_) => "blocks", Block;
+    Message::NewTransactionHashes(_) => "newtxs", Transaction;
+    Message::GetTransactions(_) => "gettxs", Transaction;
+    Message::Transactions(_) => "txs", Transaction;
+    Message::Address(_) => "address", Other;
+    Message::Version(_) => "version", Other;
+    Message::VerAck => "verack", Other;
+    Message::Auth(_) => "auth", Other;
+    Message::GetAddr => "getaddr", Other;
+    Message::Addr(_) => "addr", Other;
+    Message::GetCompactBlocks(_) => "getcmpctblocks", Block;
+    Message::CompactBlock(_) => "cmpctblock", Block;
+    Message::GetBlockTransactions(..) => "getblocktxn", Block;
+    Message::BlockTransactions(..) => "blocktxn", Block;
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = digest::digest(&digest::SHA256, payload);
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&digest.as_ref()[..CHECKSUM_LEN]);
+    checksum
+}
+
+fn invalid_frame(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+/// Put a header in front of an encoded message, compressing large payloads
+pub fn frame(command: &str, payload: &[u8]) -> Vec<u8> {
+    let mut flags = 0;
+    let mut compressed = None;
+    if payload.len() >= COMPRESSION_THRESHOLD {
+        if let Ok(c) = snap::raw::Encoder::new().compress_vec(payload) {
+            if c.len() < payload.len() {
+                flags |= FLAG_COMPRESSED;
+                compressed = Some(c);
+            }
+        }
+    }
+    let body = compressed.as_ref().map(|c| c.as_slice()).unwrap_or(payload);
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    let mut name = [0u8; COMMAND_LEN];
+    name[..command.len()].copy_from_slice(command.as_bytes());
+    frame.extend_from_slice(&name);
+    frame.push(flags);
+    frame.extend_from_slice(&checksum(payload));
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Check the header of a frame received from a peer and return its command and the encoded message
+pub fn unframe(frame: &[u8]) -> std::io::Result<(&'static str, Vec<u8>)> {
+    if frame.len() < HEADER_LEN {
+        return Err(invalid_frame(format!("frame of {} bytes is shorter than its header", frame.len())));
+    }
+    let name = &frame[..COMMAND_LEN];
+    let end = name.iter().position(|b| *b == 0).unwrap_or(COMMAND_LEN);
+    let command = match COMMANDS.iter().find(|(c, _)| c.as_bytes() == &name[..end]) {
+        Some((command, _)) => *command,
+        None => return Err(invalid_frame(format!("unknown command {:?}", &name[..end]))),
+    };
+    let flags = frame[COMMAND_LEN];
+    let expected = &frame[COMMAND_LEN + 1..HEADER_LEN];
+    let body = &frame[HEADER_LEN..];
+    let payload = if flags & FLAG_COMPRESSED != 0 {
+        let len = snap::raw::decompress_len(body)
+            .map_err(|e| invalid_frame(format!("bad compressed {} payload: {}", command, e)))?;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(invalid_frame(format!("{} payload of {} bytes exceeds limit", command, len)));
+        }
+        snap::raw::Decoder::new()
+            .decompress_vec(body)
+            .map_err(|e| invalid_frame(format!("bad compressed {} payload: {}", command, e)))?
+    } else {
+        body.to_vec()
+    };
+    if checksum(&payload) != expected {
+        return Err(invalid_frame(format!("checksum mismatch in {} message", command)));
+    }
+    Ok((command, payload))
+}
+
+/// Decode a message received from a peer, refusing to read past `MAX_MESSAGE_SIZE`
+pub fn decode(bytes: &[u8]) -> bincode::Result<Message> {
+    bincode::config()
+        .limit(MAX_MESSAGE_SIZE as u64)
+        .deserialize(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_malformed() {
+        let encoded = bincode::serialize(&Message::Ping("hello".to_string())).unwrap();
+        assert!(decode(&encoded).is_ok());
+        assert!(decode(&encoded[..encoded.len() - 1]).is_err());
+        // a string claiming to be longer than any message we accept
+        let mut huge = encoded[..4].to_vec();
+        huge.extend_from_slice(&(u64::max_value()).to_le_bytes());
+        assert!(decode(&huge).is_err());
+        assert!(decode(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let small = bincode::serialize(&Message::Ping("hello".to_string())).unwrap();
+        let framed = frame("ping", &small);
+        assert_eq!(framed.len(), HEADER_LEN + small.len());
+        assert_eq!(unframe(&framed).unwrap(), ("ping", small.clone()));
+
+        // large repetitive payloads are compressed
+        let large = bincode::serialize(&Message::GetBlocks(vec![H256::from([7u8; 32]); 100])).unwrap();
+        let framed = frame("getblocks", &large);
+        assert!(framed.len() < large.len());
+        assert_eq!(unframe(&framed).unwrap(), ("getblocks", large));
+
+        let mut corrupt = frame("ping", &small);
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(unframe(&corrupt).is_err());
+        assert!(unframe(&frame("bogus", &small)).is_err());
+        assert!(unframe(&framed[..HEADER_LEN - 1]).is_err());
+    }
 }
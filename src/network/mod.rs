@@ -1,4 +1,12 @@
+pub mod addrman;
+pub mod compact;
+pub mod handshake;
+pub mod inflight;
 pub mod message;
+pub mod outbound;
 pub mod peer;
+pub mod queue;
+pub mod ratelimit;
 pub mod server;
+pub mod transport;
 pub mod worker;
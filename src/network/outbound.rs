@@ -0,0 +1,165 @@
+use super::addrman::AddrManager;
+use super::peer::Direction;
+use super::server::Handle as ServerHandle;
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{self, Instant};
+
+/// How often the outgoing connections are checked
+const MAINTAIN_INTERVAL: time::Duration = time::Duration::from_secs(1);
+/// Wait before the first reconnect to a persistent peer, doubled after every failure
+const RECONNECT_BASE_DELAY: time::Duration = time::Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: time::Duration = time::Duration::from_secs(300);
+
+/// A peer we always want to be connected to.
+struct PersistentPeer {
+    addr: SocketAddr,
+    failures: u32,
+    next_attempt: Instant,
+}
+
+impl PersistentPeer {
+    fn backoff(&mut self) {
+        self.next_attempt = Instant::now() + reconnect_delay(self.failures);
+        self.failures += 1;
+    }
+}
+
+/// Wait before reconnecting after the given number of earlier failures in a row
+fn reconnect_delay(failures: u32) -> time::Duration {
+    RECONNECT_BASE_DELAY
+        .checked_mul(1 << failures.min(16))
+        .unwrap_or(RECONNECT_MAX_DELAY)
+        .min(RECONNECT_MAX_DELAY)
+}
+
+/// Keeps the node connected to its persistent peers, reconnecting them with exponential backoff,
+/// and to a target number of outgoing peers picked from the address manager.
+pub struct Context {
+    server: ServerHandle,
+    addrman: Arc<Mutex<AddrManager>>,
+    target_outgoing: usize,
+    persistent: Vec<PersistentPeer>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    addrman: &Arc<Mutex<AddrManager>>,
+    target_outgoing: usize,
+    persistent: &[SocketAddr],
+) -> Context {
+    let now = Instant::now();
+    Context {
+        server: server.clone(),
+        addrman: Arc::clone(addrman),
+        target_outgoing,
+        persistent: persistent
+            .iter()
+            .map(|addr| PersistentPeer {
+                addr: *addr,
+                failures: 0,
+                next_attempt: now,
+            })
+            .collect(),
+    }
+}
+
+impl Context {
+    pub fn start(mut self) {
+        let target = self.target_outgoing;
+        thread::Builder::new()
+            .name("outbound".to_string())
+            .spawn(move || loop {
+                self.maintain();
+                thread::sleep(MAINTAIN_INTERVAL);
+            })
+            .unwrap();
+        info!("Outbound connection maintainer started, target {} peers", target);
+    }
+
+    fn maintain(&mut self) {
+        if let Err(e) = self.addrman.lock().unwrap().save() {
+            warn!("Error saving peer addresses: {}", e);
+        }
+        let peers = self.server.peers();
+        let mut outgoing = peers
+            .iter()
+            .filter(|p| p.direction == Direction::Outgoing)
+            .count();
+        let mut exclude: HashSet<_> = peers.iter().map(|p| p.addr).collect();
+
+        let now = Instant::now();
+        for peer in self.persistent.iter_mut() {
+            if exclude.contains(&peer.addr) {
+                peer.failures = 0;
+                continue;
+            }
+            if now < peer.next_attempt {
+                continue;
+            }
+            match self.server.connect(peer.addr) {
+                Ok(_) => {
+                    info!("Connected to persistent peer {}", peer.addr);
+                    peer.failures = 0;
+                    exclude.insert(peer.addr);
+                    outgoing += 1;
+                }
+                Err(e) => {
+                    peer.backoff();
+                    warn!(
+                        "Error connecting to persistent peer {}, retrying in {} seconds: {}",
+                        peer.addr,
+                        (peer.next_attempt - now).as_secs(),
+                        e
+                    );
+                }
+            }
+        }
+        // persistent peers are dialed by the loop above only
+        exclude.extend(self.persistent.iter().map(|p| p.addr));
+
+        while outgoing < self.target_outgoing {
+            let addr = match self.addrman.lock().unwrap().select(&exclude) {
+                Some(addr) => addr,
+                None => break,
+            };
+            exclude.insert(addr);
+            self.addrman.lock().unwrap().attempt(&addr);
+            match self.server.connect(addr) {
+                Ok(_) => {
+                    info!("Connected to outgoing peer {}", addr);
+                    outgoing += 1;
+                }
+                Err(e) => debug!("Error connecting to peer {}: {}", addr, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let mut peer = PersistentPeer {
+            addr: "10.0.0.1:6000".parse().unwrap(),
+            failures: 0,
+            next_attempt: Instant::now(),
+        };
+        // the first retry waits the base delay, doubling from there
+        let before = Instant::now();
+        peer.backoff();
+        assert!(peer.next_attempt >= before + RECONNECT_BASE_DELAY);
+        assert!(peer.next_attempt < before + RECONNECT_BASE_DELAY * 2);
+        assert_eq!(peer.failures, 1);
+        assert_eq!(reconnect_delay(0), RECONNECT_BASE_DELAY);
+        assert_eq!(reconnect_delay(1), RECONNECT_BASE_DELAY * 2);
+        assert_eq!(reconnect_delay(3), RECONNECT_BASE_DELAY * 8);
+        assert_eq!(reconnect_delay(20), RECONNECT_MAX_DELAY);
+        assert_eq!(reconnect_delay(u32::max_value()), RECONNECT_MAX_DELAY);
+    }
+}
@@ -1,10 +1,42 @@
+use super::handshake;
 use super::message;
+use super::queue::Lane;
+use super::ratelimit::TokenBucket;
+use super::transport::{self, Cipher};
+use crate::crypto::hash::H256;
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::convert::TryInto;
+use serde::Serialize;
 use std::io::{Read, Write};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Most block and transaction hashes remembered per peer, the oldest are forgotten first
+const MAX_KNOWN_INVENTORY: usize = 5000;
+/// Queued outgoing bytes above which we stop reading from the peer until it catches up
+const WRITE_QUEUE_PAUSE: usize = 2 * message::MAX_MESSAGE_SIZE;
+/// Queued outgoing bytes above which further messages are dropped and the peer is disconnected
+const MAX_WRITE_QUEUE: usize = 8 * message::MAX_MESSAGE_SIZE;
+
+/// Messages and bytes seen for one command.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Counter {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// Traffic in one direction, by command.
+pub type Traffic = BTreeMap<&'static str, Counter>;
+
+fn record(traffic: &mut Traffic, command: &'static str, bytes: usize) {
+    let counter = traffic.entry(command).or_default();
+    counter.messages += 1;
+    counter.bytes += bytes as u64;
+}
 
 enum DecodeState {
     Length,
@@ -13,7 +45,8 @@ enum DecodeState {
 
 pub enum ReadResult {
     Continue,
-    Message(Vec<u8>),
+    /// A validated message and the command in its frame header
+    Message(&'static str, Vec<u8>),
     EOF,
 }
 
@@ -23,6 +56,11 @@ pub struct ReadContext {
     msg_length: usize,
     read_length: usize,
     state: DecodeState,
+    /// Total bytes received from the socket
+    pub bytes_read: u64,
+    /// Decrypts frames once the peer switched to the encrypted transport
+    pub cipher: Option<Cipher>,
+    pub traffic: Traffic,
 }
 
 impl ReadContext {
@@ -39,12 +77,20 @@ impl ReadContext {
                 trace!("Read {} bytes from socket", size);
                 // we got some data, move the cursor
                 self.read_length += size;
+                self.bytes_read += size as u64;
                 if self.read_length == self.msg_length {
                     // buffer filled, process the buffer
                     match self.state {
                         DecodeState::Length => {
                             let message_length =
                                 u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
+                            let max_length = message::MAX_MESSAGE_SIZE + message::HEADER_LEN + transport::TAG_LEN;
+                            if message_length as usize > max_length {
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!("message length {} exceeds limit", message_length),
+                                ));
+                            }
                             self.state = DecodeState::Payload;
                             self.read_length = 0;
                             self.msg_length = message_length as usize;
@@ -55,12 +101,17 @@ impl ReadContext {
                             Ok(ReadResult::Continue)
                         }
                         DecodeState::Payload => {
-                            let new_payload: Vec<u8> = self.buffer[0..self.msg_length].to_vec();
+                            let mut frame: Vec<u8> = self.buffer[0..self.msg_length].to_vec();
+                            if let Some(cipher) = &mut self.cipher {
+                                frame = cipher.open(frame)?;
+                            }
+                            let (command, new_payload) = message::unframe(&frame)?;
+                            record(&mut self.traffic, command, self.msg_length + std::mem::size_of::<u32>());
                             self.state = DecodeState::Length;
                             self.read_length = 0;
                             self.msg_length = std::mem::size_of::<u32>();
                             trace!("Received full message");
-                            Ok(ReadResult::Message(new_payload))
+                            Ok(ReadResult::Message(command, new_payload))
                         }
                     }
                 } else {
@@ -83,14 +134,27 @@ enum WriteState {
     Payload,
 }
 
+/// An item in a peer's write queue.
+pub enum Outgoing {
+    Frame(&'static str, Vec<u8>),
+    /// Encrypt every frame queued after this one
+    StartEncryption(Cipher),
+}
+
 pub struct WriteContext {
     writer: std::io::BufWriter<mio::net::TcpStream>,
-    pub queue: channel::Receiver<Vec<u8>>,
+    pub queue: channel::Receiver<Outgoing>,
     len_buffer: [u8; std::mem::size_of::<u32>()],
     msg_buffer: Vec<u8>,
     msg_length: usize,
     written_length: usize,
     state: WriteState,
+    /// Total bytes written to the socket
+    pub bytes_written: u64,
+    cipher: Option<Cipher>,
+    pub traffic: Traffic,
+    /// Bytes of frames waiting in the queue, shared with the handles
+    queued: Arc<AtomicUsize>,
 }
 
 impl WriteContext {
@@ -112,6 +176,7 @@ impl WriteContext {
                             return Ok(WriteResult::EOF);
                         }
                         self.written_length += written;
+                        self.bytes_written += written as u64;
                         continue;
                     }
                 }
@@ -121,7 +186,19 @@ impl WriteContext {
                         // first flush the writer
                         self.writer.flush()?;
                         let msg = match self.queue.try_recv() {
-                            Ok(msg) => msg,
+                            Ok(Outgoing::Frame(command, msg)) => {
+                                self.queued.fetch_sub(msg.len(), Ordering::SeqCst);
+                                let msg = match &mut self.cipher {
+                                    Some(cipher) => cipher.seal(msg),
+                                    None => msg,
+                                };
+                                record(&mut self.traffic, command, msg.len() + std::mem::size_of::<u32>());
+                                msg
+                            }
+                            Ok(Outgoing::StartEncryption(cipher)) => {
+                                self.cipher = Some(cipher);
+                                continue;
+                            }
                             Err(e) => match e {
                                 mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
                                 mpsc::TryRecvError::Disconnected => {
@@ -147,6 +224,7 @@ impl WriteContext {
                             return Ok(WriteResult::EOF);
                         }
                         self.written_length += written;
+                        self.bytes_written += written as u64;
                         continue;
                     }
                 }
@@ -158,6 +236,8 @@ impl WriteContext {
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    id: usize,
+    inbound_limit: TokenBucket,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
@@ -169,7 +249,11 @@ pub fn new(
         msg_length: std::mem::size_of::<u32>(),
         read_length: 0,
         state: DecodeState::Length,
+        bytes_read: 0,
+        cipher: None,
+        traffic: Traffic::new(),
     };
+    let queued = Arc::new(AtomicUsize::new(0));
     let bufwriter = std::io::BufWriter::new(writer_stream);
     let (write_sender, write_receiver) = channel::channel();
     let write_ctx = WriteContext {
@@ -180,49 +264,249 @@ pub fn new(
         msg_length: 0,
         written_length: 0,
         state: WriteState::Payload,
+        bytes_written: 0,
+        cipher: None,
+        traffic: Traffic::new(),
+        queued: Arc::clone(&queued),
     };
     let handle = Handle {
         write_queue: write_sender,
+        id,
         addr,
+        known: Arc::new(Mutex::new(KnownInventory::default())),
+        queued,
+        overflowed: Arc::new(AtomicBool::new(false)),
     };
     let ctx = Context {
+        id,
         addr,
         stream,
         reader: read_ctx,
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        connected_since: SystemTime::now(),
+        handshake: handshake::State::default(),
+        ban_score: 0,
+        last_recv: Instant::now(),
+        keepalive: Keepalive::default(),
+        inbound_limit,
+        paused: false,
+        stalled: None,
     };
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
 pub struct Context {
+    /// Unique for the lifetime of the server, unlike the slot the peer occupies
+    pub id: usize,
     pub addr: std::net::SocketAddr,
     pub stream: mio::net::TcpStream,
     pub reader: ReadContext,
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    pub connected_since: SystemTime,
+    pub handshake: handshake::State,
+    /// Accumulated penalty for misbehavior
+    pub ban_score: u32,
+    /// When we last received a full message from the peer
+    pub last_recv: Instant,
+    pub keepalive: Keepalive,
+    /// Limits how fast we take data from the peer
+    pub inbound_limit: TokenBucket,
+    /// Whether we stopped reading from the peer, for sending too fast or reading too slowly
+    pub paused: bool,
+    /// A message from the peer that did not fit in the worker queue yet
+    pub stalled: Option<(Lane, &'static str, Vec<u8>)>,
+}
+
+/// Ping state of a peer.
+#[derive(Default)]
+pub struct Keepalive {
+    /// Nonce and send time of the ping we are waiting a pong for
+    pub outstanding: Option<(u64, Instant)>,
+    pub last_ping: Option<Instant>,
+    /// Round trip time of the last answered ping
+    pub rtt: Option<Duration>,
+}
+
+/// Snapshot of a connected peer, as reported by the server.
+#[derive(Debug, Clone, Serialize)]
+pub struct Info {
+    pub id: usize,
+    pub addr: std::net::SocketAddr,
+    pub direction: Direction,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Unix timestamp in seconds
+    pub connected_since: u64,
+    pub handshake_complete: bool,
+    pub encrypted: bool,
+    /// Identity key the peer proved it holds, hex encoded
+    pub identity: Option<String>,
+    /// Protocol version, services and best height the peer announced
+    pub version: Option<u32>,
+    pub services: u64,
+    pub start_height: u64,
+    pub ban_score: u32,
+    /// Round trip time of the last answered ping, in milliseconds
+    pub ping_ms: Option<f64>,
+    /// Seconds since the last message from the peer
+    pub idle_secs: u64,
+    /// Whether we stopped reading from the peer for now
+    pub paused: bool,
+    /// Bytes waiting to be sent to the peer
+    pub queued_bytes: usize,
+    pub traffic_in: Traffic,
+    pub traffic_out: Traffic,
+}
+
+impl Context {
+    /// Whether we are willing to read more from the peer right now
+    pub fn can_read(&mut self) -> bool {
+        self.stalled.is_none()
+            && !self.inbound_limit.is_exhausted(Instant::now())
+            && self.handle.queued.load(Ordering::SeqCst) <= WRITE_QUEUE_PAUSE
+    }
+
+    pub fn info(&self) -> Info {
+        Info {
+            id: self.id,
+            addr: self.addr,
+            direction: self.direction,
+            bytes_in: self.reader.bytes_read,
+            bytes_out: self.writer.bytes_written,
+            connected_since: self
+                .connected_since
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            handshake_complete: self.handshake.is_complete(),
+            encrypted: self.handshake.encrypted,
+            identity: self.handshake.identity.as_ref().map(hex::encode),
+            version: self.handshake.remote.as_ref().map(|v| v.version),
+            services: self.handshake.remote.as_ref().map(|v| v.services).unwrap_or(0),
+            start_height: self.handshake.remote.as_ref().map(|v| v.best_height).unwrap_or(0),
+            ban_score: self.ban_score,
+            ping_ms: self.keepalive.rtt.map(|d| d.as_secs_f64() * 1000.0),
+            idle_secs: self.last_recv.elapsed().as_secs(),
+            paused: self.paused,
+            queued_bytes: self.handle.queued.load(Ordering::SeqCst),
+            traffic_in: self.reader.traffic.clone(),
+            traffic_out: self.writer.traffic.clone(),
+        }
+    }
+}
+
+/// Block and transaction hashes a peer is known to have, because it announced or sent them
+/// to us or we announced them to it.
+#[derive(Default)]
+pub struct KnownInventory {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+impl KnownInventory {
+    /// Remember a hash, returns false if it was already known
+    pub fn insert(&mut self, hash: H256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            let oldest = self.order.pop_front().unwrap();
+            self.hashes.remove(&oldest);
+        }
+        true
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
+    id: usize,
     addr: std::net::SocketAddr,
-    write_queue: channel::Sender<Vec<u8>>,
+    write_queue: channel::Sender<Outgoing>,
+    known: Arc<Mutex<KnownInventory>>,
+    queued: Arc<AtomicUsize>,
+    /// Set when a message was dropped because the write queue was full
+    overflowed: Arc<AtomicBool>,
 }
 
 impl Handle {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Record that the peer has these items, returns the ones it was not known to have
+    pub fn mark_known(&self, hashes: &[H256]) -> Vec<H256> {
+        let mut known = self.known.lock().unwrap();
+        hashes.iter().cloned().filter(|h| known.insert(*h)).collect()
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
-        if self.write_queue.send(buffer).is_err() {
+        if buffer.len() > message::MAX_MESSAGE_SIZE {
+            warn!("Not sending {} byte message to peer {}, it exceeds the size limit", buffer.len(), self.addr);
+            return;
+        }
+        let frame = message::frame(msg.command(), &buffer);
+        let queued = self.queued.fetch_add(frame.len(), Ordering::SeqCst);
+        if queued + frame.len() > MAX_WRITE_QUEUE {
+            self.queued.fetch_sub(frame.len(), Ordering::SeqCst);
+            if !self.overflowed.swap(true, Ordering::SeqCst) {
+                warn!("Write queue of peer {} is full, dropping messages", self.addr);
+            }
+            return;
+        }
+        if self.write_queue.send(Outgoing::Frame(msg.command(), frame)).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
+
+    /// Whether messages to the peer had to be dropped because it does not keep up
+    pub fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::SeqCst)
+    }
+
+    /// Encrypt everything written after the messages already queued
+    pub fn start_encryption(&self, cipher: Cipher) {
+        if self.write_queue.send(Outgoing::StartEncryption(cipher)).is_err() {
+            warn!("Failed to send write request for peer {}, channel detached", self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn known_inventory_bounded() {
+        let mut known = KnownInventory::default();
+        let first = generate_random_hash();
+        assert!(known.insert(first));
+        assert!(!known.insert(first));
+        for _ in 0..MAX_KNOWN_INVENTORY {
+            known.insert(generate_random_hash());
+        }
+        assert!(!known.contains(&first));
+        assert_eq!(known.hashes.len(), MAX_KNOWN_INVENTORY);
+    }
 }
@@ -0,0 +1,153 @@
+use super::message;
+use crossbeam::channel::{self, RecvTimeoutError, Select, TryRecvError};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Messages each lane holds before the server stops reading from the peer that sent more
+pub const LANE_CAPACITY: usize = 1024;
+
+/// Priority class of a message, higher priority lanes are always drained first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lane {
+    Block = 0,
+    Transaction = 1,
+    Other = 2,
+}
+
+const LANES: usize = 3;
+
+impl Lane {
+    /// The lane for a message, by the command in its frame header
+    pub fn of(command: &str) -> Lane {
+        message::COMMANDS
+            .iter()
+            .find(|(c, _)| *c == command)
+            .map_or(Lane::Other, |(_, lane)| *lane)
+    }
+}
+
+/// Messages waiting in each lane.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Depth {
+    pub blocks: usize,
+    pub transactions: usize,
+    pub other: usize,
+    /// Capacity of each lane
+    pub capacity: usize,
+}
+
+/// Create a queue with bounded lanes of the given capacity
+pub fn new<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (senders, receivers) = (0..LANES).map(|_| channel::bounded(capacity)).unzip();
+    (
+        Sender {
+            lanes: senders,
+            capacity,
+        },
+        Receiver { lanes: receivers },
+    )
+}
+
+pub struct Sender<T> {
+    lanes: Vec<channel::Sender<T>>,
+    capacity: usize,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            lanes: self.lanes.clone(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Queue an item without blocking, handing it back if its lane is full
+    pub fn try_send(&self, lane: Lane, item: T) -> Result<(), T> {
+        self.lanes[lane as usize].try_send(item).map_err(|e| e.into_inner())
+    }
+
+    pub fn depth(&self) -> Depth {
+        Depth {
+            blocks: self.lanes[Lane::Block as usize].len(),
+            transactions: self.lanes[Lane::Transaction as usize].len(),
+            other: self.lanes[Lane::Other as usize].len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    lanes: Vec<channel::Receiver<T>>,
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Receiver {
+            lanes: self.lanes.clone(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Take the next item from the highest priority lane that has one, waiting up to `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        loop {
+            let mut disconnected = 0;
+            for lane in &self.lanes {
+                match lane.try_recv() {
+                    Ok(item) => return Ok(item),
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => disconnected += 1,
+                }
+            }
+            if disconnected == self.lanes.len() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            // wait until any lane has something, then take from the lanes in priority order
+            let mut select = Select::new();
+            for lane in &self.lanes {
+                select.recv(lane);
+            }
+            if select.ready_timeout(timeout).is_err() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_and_bound() {
+        let (sender, receiver) = new(2);
+        sender.try_send(Lane::Other, "ping").unwrap();
+        sender.try_send(Lane::Transaction, "tx").unwrap();
+        sender.try_send(Lane::Block, "block").unwrap();
+        sender.try_send(Lane::Transaction, "tx").unwrap();
+        assert_eq!(sender.try_send(Lane::Transaction, "tx"), Err("tx"));
+        assert_eq!(sender.depth().transactions, 2);
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(receiver.recv_timeout(timeout), Ok("block"));
+        assert_eq!(receiver.recv_timeout(timeout), Ok("tx"));
+        assert_eq!(receiver.recv_timeout(timeout), Ok("tx"));
+        assert_eq!(receiver.recv_timeout(timeout), Ok("ping"));
+        assert_eq!(receiver.recv_timeout(timeout), Err(RecvTimeoutError::Timeout));
+        drop(sender);
+        assert_eq!(receiver.recv_timeout(timeout), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn lane_of_command() {
+        use super::message::Message;
+        assert_eq!(Lane::of(Message::Blocks(vec![]).command()), Lane::Block);
+        assert_eq!(Lane::of(Message::GetBlockTransactions(Default::default(), vec![]).command()), Lane::Block);
+        assert_eq!(Lane::of(Message::Transactions(vec![]).command()), Lane::Transaction);
+        assert_eq!(Lane::of(Message::GetAddr.command()), Lane::Other);
+        assert_eq!(Lane::of("bogus"), Lane::Other);
+    }
+}
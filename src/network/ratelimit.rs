@@ -0,0 +1,64 @@
+use std::time::Instant;
+
+/// A token bucket that refills at a steady rate up to its capacity.
+/// Consuming may take it below zero, so a single large message is never refused outright;
+/// the debt just has to be paid back before the bucket allows more.
+pub struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, capacity: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last {
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+            self.last = now;
+        }
+    }
+
+    pub fn consume(&mut self, amount: u64, now: Instant) {
+        self.refill(now);
+        self.tokens -= amount as f64;
+    }
+
+    /// Whether the bucket is in debt and nothing more should be taken from it yet
+    pub fn is_exhausted(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens < 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn refill_and_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, 200);
+        bucket.consume(150, start);
+        assert!(!bucket.is_exhausted(start));
+        bucket.consume(150, start);
+        assert!(bucket.is_exhausted(start));
+        // 100 tokens in debt, paid back after one second
+        assert!(bucket.is_exhausted(start + Duration::from_millis(900)));
+        assert!(!bucket.is_exhausted(start + Duration::from_millis(1100)));
+        // never refills past capacity
+        bucket.consume(201, start + Duration::from_secs(60));
+        assert!(bucket.is_exhausted(start + Duration::from_secs(60)));
+    }
+}
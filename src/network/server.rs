@@ -1,18 +1,100 @@
-use super::message;
+use super::addrman::{self, AddrManager};
+use super::handshake;
+use super::message::{self, Message, NetAddress};
 use super::peer::{self, ReadResult, WriteResult};
+use super::queue::{self, Lane};
+use super::ratelimit::TokenBucket;
+use super::transport::{self, KeyExchange};
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// How long to wait for an outgoing TCP connection to be established
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often timers of the event loop are checked
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often an idle peer is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long we wait for a pong before disconnecting
+const PING_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a new connection has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a peer may stay silent, pings included, before being disconnected
+const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(90);
+/// Ban score at which a peer is disconnected and banned
+const BAN_THRESHOLD: u32 = 100;
+/// How long a misbehaving peer stays banned
+const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest ban that can be requested through the API
+pub const MAX_BAN_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// Seconds of inbound traffic at the full rate a peer may send in a burst
+pub const INBOUND_BURST_SECS: u64 = 4;
+
+/// Caps on the number of connections in each direction, within `MAX_INCOMING_CLIENT` in total,
+/// and on how fast each peer may send to us.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub max_incoming: usize,
+    pub max_outgoing: usize,
+    /// Bytes per second we read from each peer
+    pub max_inbound_rate: u64,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_incoming: 125,
+            max_outgoing: 16,
+            max_inbound_rate: 1024 * 1024,
+        }
+    }
+}
+
+/// Offenses a peer is penalized for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Misbehavior {
+    /// Sent a message that could not be decoded
+    DecodeError,
+    /// Sent a block whose hash does not meet its difficulty
+    InvalidProofOfWork,
+    /// Sent a transaction, or a block containing one, with a bad signature
+    InvalidSignature,
+    /// Sent a block that breaks the rules of the chain, like a wrong difficulty or reward
+    InvalidBlock,
+    /// Sent blocks or transactions we never asked for
+    UnrequestedData,
+    /// Sent a message under the command of another, like transactions labeled as blocks
+    MislabeledMessage,
+}
+
+impl Misbehavior {
+    /// How much the offense adds to the peer's ban score
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::DecodeError => 50,
+            Misbehavior::InvalidProofOfWork => 100,
+            Misbehavior::InvalidSignature => 100,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::UnrequestedData => 20,
+            Misbehavior::MislabeledMessage => 50,
+        }
+    }
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
-    msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    msg_sink: queue::Sender<(&'static str, Vec<u8>, peer::Handle)>,
+    local: handshake::Local,
+    addrman: &Arc<Mutex<AddrManager>>,
+    limits: ConnectionLimits,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
@@ -24,7 +106,13 @@ pub fn new(
         addr,
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
-        new_msg_chan: msg_sink,
+        new_msg_chan: Some(msg_sink),
+        listener: None,
+        banned: HashMap::new(),
+        local,
+        addrman: Arc::clone(addrman),
+        limits,
+        next_peer_id: 0,
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
@@ -36,7 +124,18 @@ pub struct Context {
     addr: std::net::SocketAddr,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
-    new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    /// Where messages for the workers go, dropped on shutdown so they stop once drained
+    new_msg_chan: Option<queue::Sender<(&'static str, Vec<u8>, peer::Handle)>>,
+    /// Accepts incoming connections until shutdown
+    listener: Option<net::TcpListener>,
+    /// Banned IP addresses and when their ban expires
+    banned: HashMap<IpAddr, Instant>,
+    /// What we announce about ourselves in handshakes
+    local: handshake::Local,
+    addrman: Arc<Mutex<AddrManager>>,
+    limits: ConnectionLimits,
+    /// Id given to the next peer, never reused
+    next_peer_id: usize,
     _handle: Handle,
 }
 
@@ -79,7 +178,18 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let rate = self.limits.max_inbound_rate;
+        let inbound_limit = TokenBucket::new(rate, rate.saturating_mul(INBOUND_BURST_SECS));
+        let (mut ctx, handle) = peer::new(stream, direction, self.next_peer_id, inbound_limit)?;
+        self.next_peer_id += 1;
+        // both sides open with their version, nothing else is accepted until the handshake completes
+        let mut version = self.local.version();
+        if self.local.encrypt {
+            let key_exchange = KeyExchange::new();
+            version.ephemeral_key = key_exchange.public_key().to_vec();
+            ctx.handshake.key_exchange = Some(key_exchange);
+        }
+        handle.write(Message::Version(version));
 
         // register the writer queue
         self.poll.register(
@@ -97,11 +207,27 @@ impl Context {
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+    /// Register a connection we established to a peer
+    fn connect(&mut self, stream: std::net::TcpStream) -> std::io::Result<peer::Handle> {
+        if self.new_msg_chan.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "server is shutting down",
+            ));
+        }
+        let addr = stream.peer_addr()?;
+        if self.is_banned(&addr.ip()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "peer address is banned",
+            ));
+        }
+        if self.count_peers(peer::Direction::Outgoing) >= self.limits.max_outgoing {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "max outgoing peers reached",
+            ));
+        }
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -113,6 +239,14 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.is_banned(&addr.ip()) {
+            info!("Rejected incoming connection from banned peer {}", addr);
+            return Ok(());
+        }
+        if self.count_peers(peer::Direction::Incoming) >= self.limits.max_incoming {
+            info!("Rejected incoming connection from {}, max incoming peers reached", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -124,11 +258,221 @@ impl Context {
         Ok(())
     }
 
+    fn count_peers(&self, direction: peer::Direction) -> usize {
+        self.peer_list
+            .iter()
+            .filter(|peer_id| self.peers[**peer_id].direction == direction)
+            .count()
+    }
+
+    /// Check whether an IP address is banned, forgetting the ban once it has expired
+    fn is_banned(&mut self, ip: &IpAddr) -> bool {
+        match self.banned.get(ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.banned.remove(ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Process a message from a peer that has not completed the handshake yet.
+    /// Returns false if the peer should be disconnected.
+    fn process_handshake(&mut self, peer_id: usize, msg: Vec<u8>) -> bool {
+        let peer = &mut self.peers[peer_id];
+        let msg: Message = match message::decode(&msg) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Undecodable handshake message from peer {}, disconnecting: {}", peer.addr, e);
+                return false;
+            }
+        };
+        match msg {
+            Message::Version(version) => {
+                if peer.handshake.remote.is_some() {
+                    warn!("Duplicate version message from peer {}, disconnecting", peer.addr);
+                    return false;
+                }
+                if let Err(e) = self.local.check(&version) {
+                    warn!("Rejecting peer {}: {}", peer.addr, e);
+                    return false;
+                }
+                debug!("Peer {} runs protocol version {} at height {}", peer.addr, version.version, version.best_height);
+                // switch to the encrypted transport if both sides offered it
+                match peer.handshake.key_exchange.take() {
+                    Some(key_exchange) if !version.ephemeral_key.is_empty() => {
+                        let local_ephemeral = key_exchange.public_key().to_vec();
+                        let (send, recv) = match key_exchange.agree(&version.ephemeral_key) {
+                            Ok(ciphers) => ciphers,
+                            Err(e) => {
+                                warn!("Key agreement with peer {} failed, disconnecting: {}", peer.addr, e);
+                                return false;
+                            }
+                        };
+                        peer.handle.start_encryption(send);
+                        peer.reader.cipher = Some(recv);
+                        peer.handshake.encrypted = true;
+                        if let Some(identity) = &self.local.identity {
+                            let transcript = transport::transcript(&local_ephemeral, &version.ephemeral_key);
+                            peer.handle.write(Message::Auth(identity.sign(&transcript)));
+                        }
+                        peer.handshake.local_ephemeral = local_ephemeral;
+                    }
+                    _ => {}
+                }
+                peer.handshake.remote = Some(version);
+                peer.handle.write(Message::VerAck);
+            }
+            Message::Auth(signature) => {
+                let remote = match &peer.handshake.remote {
+                    Some(remote) if peer.handshake.encrypted && !remote.identity_key.is_empty() => remote,
+                    _ => {
+                        warn!("Unexpected identity proof from peer {}, disconnecting", peer.addr);
+                        return false;
+                    }
+                };
+                let transcript = transport::transcript(&remote.ephemeral_key, &peer.handshake.local_ephemeral);
+                if !transport::verify_identity(&remote.identity_key, &transcript, &signature) {
+                    warn!("Invalid identity proof from peer {}, disconnecting", peer.addr);
+                    return false;
+                }
+                peer.handshake.identity = Some(remote.identity_key.clone());
+            }
+            Message::VerAck => {
+                if peer.handshake.remote.is_none() {
+                    warn!("Peer {} acknowledged before sending its version, disconnecting", peer.addr);
+                    return false;
+                }
+                if let Err(e) = self.local.check_identity(&peer.addr, &peer.handshake) {
+                    warn!("Rejecting peer {}: {}", peer.addr, e);
+                    return false;
+                }
+                peer.handshake.verack_received = true;
+            }
+            _ => {
+                warn!("Peer {} sent a message before completing the handshake, disconnecting", peer.addr);
+                return false;
+            }
+        }
+        if peer.handshake.is_complete() {
+            let transport = if peer.handshake.encrypted { "encrypted" } else { "plaintext" };
+            info!("Completed handshake with peer {} over {} transport", peer.addr, transport);
+            // ask the peers we chose for more addresses, and tell everyone where to find us
+            if let peer::Direction::Outgoing = peer.direction {
+                self.addrman.lock().unwrap().good(&peer.addr);
+                peer.handle.write(Message::GetAddr);
+            }
+            if !self.addr.ip().is_unspecified() {
+                peer.handle.write(Message::Addr(vec![NetAddress {
+                    addr: self.addr,
+                    timestamp: addrman::now(),
+                }]));
+            }
+        }
+        true
+    }
+
+    /// Raise the ban score of a peer, disconnecting and banning it once it reaches the threshold
+    fn penalize(&mut self, peer_id: usize, offense: Misbehavior) {
+        let peer = &mut self.peers[peer_id];
+        peer.ban_score += offense.score();
+        warn!("Peer {} misbehaved ({:?}), ban score {}", peer.addr, offense, peer.ban_score);
+        if peer.ban_score < BAN_THRESHOLD {
+            return;
+        }
+        let addr = peer.addr;
+        self.remove_peer(peer_id);
+        info!("Banning misbehaving peer {} for {} seconds", addr, BAN_DURATION.as_secs());
+        self.banned.insert(addr.ip(), Instant::now() + BAN_DURATION);
+    }
+
+    /// Find the slot of the connected peer with the given id
+    fn find_peer(&self, id: usize) -> Option<usize> {
+        self.peer_list.iter().find(|peer_id| self.peers[**peer_id].id == id).cloned()
+    }
+
+    /// Ping peers and disconnect those that are unresponsive
+    fn tick(&mut self) -> std::io::Result<()> {
+        let now = Instant::now();
+        let mut stale = vec![];
+        let mut resumed = vec![];
+        for peer_id in &self.peer_list {
+            let peer = &mut self.peers[*peer_id];
+            if peer.handle.overflowed() {
+                info!("Peer {} is not reading what we send, disconnecting", peer.addr);
+                stale.push(*peer_id);
+                continue;
+            }
+            if let (Some((lane, command, m)), Some(chan)) = (peer.stalled.take(), &self.new_msg_chan) {
+                if let Err((_, m, _)) = chan.try_send(lane, (command, m, peer.handle.clone())) {
+                    peer.stalled = Some((lane, command, m));
+                }
+            }
+            if peer.paused && peer.can_read() {
+                resumed.push(*peer_id);
+            }
+            let connected_for = peer.connected_since.elapsed().unwrap_or_default();
+            if !peer.handshake.is_complete() {
+                if connected_for > HANDSHAKE_TIMEOUT {
+                    info!("Peer {} did not complete the handshake in time, disconnecting", peer.addr);
+                    stale.push(*peer_id);
+                }
+                continue;
+            }
+            if now.duration_since(peer.last_recv) > INACTIVITY_TIMEOUT {
+                info!("Peer {} has been inactive, disconnecting", peer.addr);
+                stale.push(*peer_id);
+                continue;
+            }
+            match peer.keepalive.outstanding {
+                Some((_, sent)) => {
+                    if now.duration_since(sent) > PING_TIMEOUT {
+                        info!("Peer {} did not answer our ping, disconnecting", peer.addr);
+                        stale.push(*peer_id);
+                    }
+                }
+                None => {
+                    let due = match peer.keepalive.last_ping {
+                        Some(last) => now.duration_since(last) >= PING_INTERVAL,
+                        None => true,
+                    };
+                    if due {
+                        let nonce: u64 = rand::random();
+                        peer.keepalive.outstanding = Some((nonce, now));
+                        peer.keepalive.last_ping = Some(now);
+                        peer.handle.write(Message::Ping(nonce.to_string()));
+                    }
+                }
+            }
+        }
+        for peer_id in stale {
+            self.remove_peer(peer_id);
+        }
+        // edge-triggered polling won't report data that arrived while paused, read it now
+        for peer_id in resumed {
+            if self.peers.contains(peer_id) {
+                trace!("Resuming reads from peer {}", peer_id);
+                self.peers[peer_id].paused = false;
+                self.process_readable(peer_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop a peer from the connection set, closing its socket
+    fn remove_peer(&mut self, peer_id: usize) {
+        let peer = self.peers.remove(peer_id);
+        let _ = peer.stream.shutdown(std::net::Shutdown::Both);
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
     fn process_control(&mut self, req: ControlSignal) -> std::io::Result<()> {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
+                let handle = self.connect(req.stream);
                 req.result_chan.send(handle).unwrap();
             }
             ControlSignal::BroadcastMessage(msg) => {
@@ -136,8 +480,114 @@ impl Context {
                 // println!("Peer list: {:?}", &self.peer_list);
 
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    let peer = &self.peers[*peer_id];
+                    if !peer.handshake.is_complete() {
+                        continue;
+                    }
+                    // only announce what the peer is not known to have already
+                    let msg = match &msg {
+                        Message::NewBlockHashes(hashes) => {
+                            let unknown = peer.handle.mark_known(hashes);
+                            if unknown.is_empty() {
+                                continue;
+                            }
+                            Message::NewBlockHashes(unknown)
+                        }
+                        Message::NewTransactionHashes(hashes) => {
+                            let unknown = peer.handle.mark_known(hashes);
+                            if unknown.is_empty() {
+                                continue;
+                            }
+                            Message::NewTransactionHashes(unknown)
+                        }
+                        msg => msg.clone(),
+                    };
+                    peer.handle.write(msg);
+                }
+            }
+            ControlSignal::SendTo(id, msg) => {
+                trace!("Processing SendTo command");
+                match self.find_peer(id).map(|peer_id| &self.peers[peer_id]) {
+                    Some(peer) if peer.handshake.is_complete() => peer.handle.write(msg),
+                    _ => debug!("Dropping message to peer {} which is not connected", id),
+                }
+            }
+            ControlSignal::QueueDepth(result_chan) => {
+                trace!("Processing QueueDepth command");
+                let depth = match &self.new_msg_chan {
+                    Some(chan) => chan.depth(),
+                    None => queue::Depth::default(),
+                };
+                result_chan.send(depth).unwrap();
+            }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self
+                    .peer_list
+                    .iter()
+                    .map(|peer_id| self.peers[*peer_id].info())
+                    .collect();
+                result_chan.send(peers).unwrap();
+            }
+            ControlSignal::DisconnectPeer(id, result_chan) => {
+                trace!("Processing DisconnectPeer command");
+                let peer_id = self.find_peer(id);
+                if let Some(peer_id) = peer_id {
+                    info!("Disconnecting peer {}", self.peers[peer_id].addr);
+                    self.remove_peer(peer_id);
+                }
+                result_chan.send(peer_id.is_some()).unwrap();
+            }
+            ControlSignal::Misbehaving(id, offense) => {
+                trace!("Processing Misbehaving command");
+                if let Some(peer_id) = self.find_peer(id) {
+                    self.penalize(peer_id, offense);
+                }
+            }
+            ControlSignal::PongReceived(id, nonce) => {
+                trace!("Processing PongReceived command");
+                if let Some(peer_id) = self.find_peer(id) {
+                    let addr = self.peers[peer_id].addr;
+                    let keepalive = &mut self.peers[peer_id].keepalive;
+                    match keepalive.outstanding {
+                        Some((expected, sent)) if expected.to_string() == nonce => {
+                            keepalive.rtt = Some(sent.elapsed());
+                            keepalive.outstanding = None;
+                        }
+                        _ => debug!("Unexpected pong {} from peer {}", nonce, addr),
+                    }
+                }
+            }
+            ControlSignal::BanAddress(ip, duration) => {
+                trace!("Processing BanAddress command");
+                info!("Banning {} for {} seconds", ip, duration.as_secs());
+                let now = Instant::now();
+                let until = now
+                    .checked_add(duration)
+                    .unwrap_or_else(|| now + MAX_BAN_DURATION);
+                self.banned.insert(ip, until);
+                let banned_peers: Vec<usize> = self
+                    .peer_list
+                    .iter()
+                    .filter(|peer_id| self.peers[**peer_id].addr.ip() == ip)
+                    .cloned()
+                    .collect();
+                for peer_id in banned_peers {
+                    self.remove_peer(peer_id);
+                }
+            }
+            ControlSignal::Shutdown(result_chan) => {
+                trace!("Processing Shutdown command");
+                info!("P2P server shutting down, disconnecting {} peers", self.peer_list.len());
+                if let Some(listener) = self.listener.take() {
+                    self.poll.deregister(&listener)?;
                 }
+                for peer_id in self.peer_list.clone() {
+                    self.remove_peer(peer_id);
+                }
+                // workers exit once they have processed what is already queued
+                self.new_msg_chan = None;
+                result_chan.send(()).unwrap();
             }
         }
         Ok(())
@@ -160,15 +610,20 @@ impl Context {
 
     fn process_readable(&mut self, peer_id: usize) -> std::io::Result<()> {
         // we are using edge-triggered events, loop until block
-        let peer = &mut self.peers[peer_id];
         loop {
+            let peer = &mut self.peers[peer_id];
+            if !peer.can_read() {
+                if !peer.paused {
+                    debug!("Pausing reads from peer {}", peer.addr);
+                    peer.paused = true;
+                }
+                break;
+            }
             match peer.reader.read() {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -176,10 +631,26 @@ impl Context {
                     // no full message has been received
                     continue;
                 }
-                Ok(ReadResult::Message(m)) => {
+                Ok(ReadResult::Message(command, m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    peer.last_recv = Instant::now();
+                    peer.inbound_limit.consume(m.len() as u64, peer.last_recv);
+                    if peer.handshake.is_complete() {
+                        let lane = Lane::of(command);
+                        let chan = match &self.new_msg_chan {
+                            Some(chan) => chan,
+                            None => break,
+                        };
+                        if let Err((_, m, _)) = chan.try_send(lane, (command, m, peer.handle.clone())) {
+                            // hold on to it and stop reading from the peer until the workers catch up
+                            debug!("Worker queue full for {} from peer {}", command, peer.addr);
+                            peer.stalled = Some((lane, command, m));
+                        }
+                    } else if !self.process_handshake(peer_id, m) {
+                        self.remove_peer(peer_id);
+                        break;
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -189,9 +660,7 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.remove_peer(peer_id);
                         break;
                     }
                 }
@@ -226,9 +695,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -248,9 +715,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                 }
             }
         }
@@ -281,12 +746,18 @@ impl Context {
         )?;
 
         info!("P2P server listening at {}", server.local_addr()?);
+        self.listener = Some(server);
 
         // initialize space for polled events
         let mut events = mio::Events::with_capacity(MAX_EVENT);
+        let mut last_tick = Instant::now();
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(&mut events, Some(TICK_INTERVAL))?;
+            if last_tick.elapsed() >= TICK_INTERVAL {
+                self.tick()?;
+                last_tick = Instant::now();
+            }
 
             for event in events.iter() {
                 match event.token() {
@@ -316,7 +787,11 @@ impl Context {
                         // we are using edge-triggered events, loop until block
                         loop {
                             // accept the connection
-                            match server.accept() {
+                            let accepted = match &self.listener {
+                                Some(listener) => listener.accept(),
+                                None => break,
+                            };
+                            match accepted {
                                 Ok((stream, client_addr)) => {
                                     self.accept(stream, client_addr).unwrap();
                                 }
@@ -355,6 +830,10 @@ impl Context {
                             }
                             1 => {
                                 trace!("Peer {} outgoing queue readable", peer_id);
+                                // the peer may have been removed earlier in this batch
+                                if !self.peers.contains(peer_id) {
+                                    continue;
+                                }
                                 self.register_write_interest(peer_id)?;
                             }
                             _ => unreachable!(),
@@ -372,10 +851,13 @@ pub struct Handle {
 }
 
 impl Handle {
+    /// Connect to a peer. The TCP connection is established on the calling thread.
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        debug!("Establishing connection to peer {}", addr);
+        let stream = std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
-            addr,
+            stream,
             result_chan: sender,
         };
         self.control_chan
@@ -389,14 +871,103 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    /// Send a message to one peer, identified by the id in its `peer::Info`.
+    /// The message is dropped if the peer has disconnected.
+    pub fn send_to(&self, peer_id: usize, msg: message::Message) {
+        self.control_chan
+            .send(ControlSignal::SendTo(peer_id, msg))
+            .unwrap();
+    }
+
+    pub fn peers(&self) -> Vec<peer::Info> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::ListPeers(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Messages waiting for the workers, by priority lane
+    pub fn queue_depth(&self) -> queue::Depth {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::QueueDepth(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Disconnect a peer, identified by the id in its `peer::Info`.
+    /// Returns whether such a peer was connected.
+    pub fn disconnect(&self, peer_id: usize) -> bool {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::DisconnectPeer(peer_id, sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Pass a pong received from a peer, to be matched against our ping
+    pub fn pong_received(&self, peer_id: usize, nonce: String) {
+        self.control_chan
+            .send(ControlSignal::PongReceived(peer_id, nonce))
+            .unwrap();
+    }
+
+    /// Report an offense by a peer. Peers are told apart by id, as several may share an address.
+    pub fn misbehaving(&self, peer_id: usize, offense: Misbehavior) {
+        self.control_chan
+            .send(ControlSignal::Misbehaving(peer_id, offense))
+            .unwrap();
+    }
+
+    /// Disconnect all peers from the given IP and refuse connections with it for a period
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.control_chan
+            .send(ControlSignal::BanAddress(ip, duration))
+            .unwrap();
+    }
+
+    /// Stop accepting connections, disconnect all peers and close the worker queue.
+    /// Returns once done; the server keeps answering the other requests until the process exits.
+    pub fn shutdown(&self) {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::Shutdown(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    SendTo(usize, message::Message),
+    ListPeers(cbchannel::Sender<Vec<peer::Info>>),
+    QueueDepth(cbchannel::Sender<queue::Depth>),
+    DisconnectPeer(usize, cbchannel::Sender<bool>),
+    BanAddress(IpAddr, Duration),
+    Misbehaving(usize, Misbehavior),
+    PongReceived(usize, String),
+    Shutdown(cbchannel::Sender<()>),
 }
 
 struct ConnectRequest {
-    addr: std::net::SocketAddr,
+    stream: std::net::TcpStream,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A server that is never started, for testing components that hold a handle to it.
+    /// The context has to be kept alive, it queues what the handle sends.
+    pub fn unstarted() -> (Context, Handle) {
+        let (msg_sink, _) = queue::new(1);
+        let local = handshake::Local::new(handshake::Network::Regtest, Box::new(|| 0));
+        let addrman = Arc::new(Mutex::new(AddrManager::new(None, None).unwrap()));
+        let addr = "127.0.0.1:0".parse().unwrap();
+        new(addr, msg_sink, local, &addrman, ConnectionLimits::default()).unwrap()
+    }
+}
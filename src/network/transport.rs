@@ -0,0 +1,158 @@
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use ring::{aead, agreement, hkdf};
+use std::path::Path;
+
+/// Bytes an encrypted frame is longer than its plaintext
+pub const TAG_LEN: usize = 16;
+
+const KEY_INFO: &[u8] = b"ece598 p2p key";
+const AUTH_CONTEXT: &[u8] = b"ece598 p2p auth";
+
+fn invalid_data<E: std::fmt::Debug>(e: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// Encrypts or decrypts the frames going one way on a connection.
+pub struct Cipher {
+    key: aead::LessSafeKey,
+    /// Frames processed so far, used as the nonce so each is used once
+    counter: u64,
+}
+
+impl Cipher {
+    fn nonce(&mut self) -> aead::Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    pub fn seal(&mut self, mut frame: Vec<u8>) -> Vec<u8> {
+        let nonce = self.nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut frame)
+            .expect("frame too large to encrypt");
+        frame
+    }
+
+    /// Decrypt a frame, failing if it was tampered with, replayed or reordered
+    pub fn open(&mut self, mut frame: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let nonce = self.nonce();
+        let len = self
+            .key
+            .open_in_place(nonce, aead::Aad::empty(), &mut frame)
+            .map_err(|_| invalid_data("frame failed authentication"))?
+            .len();
+        frame.truncate(len);
+        Ok(frame)
+    }
+}
+
+/// Our half of an X25519 key agreement for one connection.
+pub struct KeyExchange {
+    private: agreement::EphemeralPrivateKey,
+    public: Vec<u8>,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let rng = SystemRandom::new();
+        let private = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).unwrap();
+        let public = private.compute_public_key().unwrap().as_ref().to_vec();
+        KeyExchange { private, public }
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    /// Derive the ciphers for what we send and what we receive from the peer's public key
+    pub fn agree(self, remote: &[u8]) -> std::io::Result<(Cipher, Cipher)> {
+        let local = self.public;
+        let remote_key = agreement::UnparsedPublicKey::new(&agreement::X25519, remote);
+        agreement::agree_ephemeral(self.private, &remote_key, invalid_data("bad public key"), |secret| {
+            // each direction gets its own key, named after the sender's public key
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(secret);
+            let derive = |sender: &[u8]| -> std::io::Result<Cipher> {
+                let info = [KEY_INFO, sender];
+                let okm = prk.expand(&info, &aead::CHACHA20_POLY1305).map_err(invalid_data)?;
+                Ok(Cipher {
+                    key: aead::LessSafeKey::new(aead::UnboundKey::from(okm)),
+                    counter: 0,
+                })
+            };
+            Ok((derive(&local)?, derive(remote)?))
+        })
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        KeyExchange::new()
+    }
+}
+
+/// What a node signs to prove its identity on a connection, binding both ephemeral keys
+pub fn transcript(signer_ephemeral: &[u8], other_ephemeral: &[u8]) -> Vec<u8> {
+    [AUTH_CONTEXT, signer_ephemeral, other_ephemeral].concat()
+}
+
+/// A long-lived Ed25519 key a node can prove it holds, so operators can pin it.
+pub struct Identity {
+    key_pair: Ed25519KeyPair,
+}
+
+impl Identity {
+    /// Load the identity key from a PKCS#8 file, generating and saving a new one if it does not exist
+    pub fn load_or_generate(path: &Path) -> std::io::Result<Self> {
+        let pkcs8 = if path.exists() {
+            std::fs::read(path)?
+        } else {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(invalid_data)?;
+            std::fs::write(path, pkcs8.as_ref())?;
+            pkcs8.as_ref().to_vec()
+        };
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(invalid_data)?;
+        Ok(Identity { key_pair })
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.key_pair.public_key().as_ref().to_vec()
+    }
+
+    pub fn sign(&self, transcript: &[u8]) -> Vec<u8> {
+        self.key_pair.sign(transcript).as_ref().to_vec()
+    }
+}
+
+pub fn verify_identity(public_key: &[u8], transcript: &[u8], sig: &[u8]) -> bool {
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(transcript, sig)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_round_trip() {
+        let alice = KeyExchange::new();
+        let bob = KeyExchange::new();
+        let alice_public = alice.public_key().to_vec();
+        let (mut alice_send, mut alice_recv) = alice.agree(bob.public_key()).unwrap();
+        let (mut bob_send, mut bob_recv) = bob.agree(&alice_public).unwrap();
+
+        let sealed = alice_send.seal(b"hello".to_vec());
+        assert_eq!(sealed.len(), 5 + TAG_LEN);
+        assert_eq!(bob_recv.open(sealed).unwrap(), b"hello");
+        let reply = bob_send.seal(b"hi".to_vec());
+        assert_eq!(alice_recv.open(reply).unwrap(), b"hi");
+
+        // tampered frames are rejected
+        let mut sealed = alice_send.seal(b"again".to_vec());
+        sealed[0] ^= 1;
+        assert!(bob_recv.open(sealed).is_err());
+    }
+}
@@ -1,41 +1,58 @@
-use super::message::Message;
+use super::addrman::AddrManager;
+use super::compact::{CompactBlock, PartialBlock, Reconstruction};
+use super::inflight::{Answer, Inflight, Kind};
+use super::message::{self, Message, MAX_ADDR_PER_MESSAGE};
 use super::peer;
-use crate::network::server::Handle as ServerHandle;
+use super::queue;
+use crate::network::server::{Handle as ServerHandle, Misbehavior};
 use crossbeam::channel;
-use log::{debug, warn};
+use log::{debug, trace, warn};
 
-use std::sync::{Arc, Mutex, MutexGuard};
-use crate::blockchain::{Blockchain};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use crate::blockchain::{BlockError, Blockchain};
 use crate::crypto::hash::{H256, Hashable, H160};
 use crate::block::{Block};
 use log::info;
 
 
 use std::thread;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use serde::Serialize;
 use crate::transaction;
 use crate::transaction::{Transaction, verify, SignedTrans};
 use crate::state::State;
 use crate::mempool::Mempool;
+use crate::locks;
+
+/// Largest `Addr` message that is relayed to other peers
+const ADDR_RELAY_LIMIT: usize = 10;
+/// How often timed out requests are retried with other peers
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct Context {
-    msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_chan: queue::Receiver<(&'static str, Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
-    blkchain: Arc<Mutex<Blockchain>>,
+    blkchain: Arc<RwLock<Blockchain>>,
     mem_pool: Arc<Mutex<Mempool>>,
     address_list:Arc<Mutex<Vec<H160>>>,
+    /// Blocks and transactions we asked peers for and have not received yet
+    inflight: Arc<Mutex<Inflight>>,
+    /// Compact blocks waiting for transactions we had to request
+    partial: Arc<Mutex<HashMap<H256, PartialBlock>>>,
+    addrman: Arc<Mutex<AddrManager>>,
 }
 
 pub fn new(
     num_worker: usize,
-    msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_src: queue::Receiver<(&'static str, Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
-    blkchain: &Arc<Mutex<Blockchain>>,
+    blkchain: &Arc<RwLock<Blockchain>>,
     mempool: &Arc<Mutex<Mempool>>,
     address_list:&Arc<Mutex<Vec<H160>>>,
+    addrman: &Arc<Mutex<AddrManager>>,
 ) -> Context {
     Context {
         msg_chan: msg_src,
@@ -44,27 +61,235 @@ pub fn new(
         blkchain: Arc::clone(blkchain),
         mem_pool: Arc::clone(mempool),
         address_list:Arc::clone(address_list),
+        inflight: Arc::new(Mutex::new(Inflight::new())),
+        partial: Arc::new(Mutex::new(HashMap::new())),
+        addrman: Arc::clone(addrman),
     }
 }
 
 
 impl Context {
-    pub fn start(self) {
+    /// Start the worker threads, which run until the server closes the queue and it is drained
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
         let num_worker = self.num_worker;
-        for i in 0..num_worker {
-            let cloned = self.clone();
-            thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
+        (0..num_worker)
+            .map(|i| {
+                let cloned = self.clone();
+                thread::spawn(move || {
+                    cloned.worker_loop();
+                    info!("Worker thread {} exited", i);
+                })
+            })
+            .collect()
+    }
+
+    /// Keep the announced hashes that nobody is being asked for yet, and record that we ask this peer
+    fn request(&self, peer: &peer::Handle, kind: Kind, hashes: Vec<H256>) -> Vec<H256> {
+        let mut inflight = self.inflight.lock().unwrap();
+        hashes
+            .into_iter()
+            .filter(|hash| inflight.announce(kind, *hash, peer.id()))
+            .collect()
+    }
+
+    /// Mark a hash as received from a peer, returns false if the item should be dropped
+    fn take_request(&self, peer: &peer::Handle, hash: &H256) -> bool {
+        let answer = self.inflight.lock().unwrap().received(hash, peer.id());
+        self.accept_answer(peer, hash, answer)
+    }
+
+    /// Like `take_request`, for parts of a block that is still being requested
+    fn expecting(&self, peer: &peer::Handle, hash: &H256) -> bool {
+        let answer = self.inflight.lock().unwrap().answer(hash, peer.id());
+        self.accept_answer(peer, hash, answer)
+    }
+
+    /// Late answers to requests that were retried or given up on are dropped without penalty
+    fn accept_answer(&self, peer: &peer::Handle, hash: &H256, answer: Answer) -> bool {
+        match answer {
+            Answer::Requested => true,
+            Answer::Late => {
+                debug!("Dropping late answer for {} from peer {}", hash, peer.addr());
+                false
+            }
+            Answer::Unrequested => {
+                self.server.misbehaving(peer.id(), Misbehavior::UnrequestedData);
+                false
+            }
+        }
+    }
+
+    /// Ask other peers for the items whose request timed out. Blocks are asked for in full
+    /// the second time, in case the compact block could not be completed.
+    fn retry_requests(&self) {
+        let mut inflight = self.inflight.lock().unwrap();
+        let retries = inflight.expire(Instant::now());
+        self.partial.lock().unwrap().retain(|hash, _| inflight.contains(hash));
+        drop(inflight);
+        let mut batches: HashMap<(usize, Kind), Vec<H256>> = HashMap::new();
+        for (peer_id, kind, hash) in retries {
+            batches.entry((peer_id, kind)).or_default().push(hash);
+        }
+        for ((peer_id, kind), hashes) in batches {
+            debug!("Retrying {} requests with peer {}", hashes.len(), peer_id);
+            let msg = match kind {
+                Kind::Block => Message::GetBlocks(hashes),
+                Kind::Transaction => Message::GetTransactions(hashes),
+            };
+            self.server.send_to(peer_id, msg);
+        }
+    }
+
+    /// Connect a compact block if we have all its transactions, otherwise ask the peer for the rest
+    fn complete_block(&self, peer: &peer::Handle, hash: H256, partial: PartialBlock) {
+        match partial.reconstruct() {
+            Reconstruction::Complete(block) => self.receive_blocks(peer, vec![block]),
+            Reconstruction::Missing(missing) => {
+                debug!("Requesting {} missing transactions of block {}", missing.len(), hash);
+                self.partial.lock().unwrap().insert(hash, partial);
+                peer.write(Message::GetBlockTransactions(hash, missing));
+            }
+            Reconstruction::Mismatch => {
+                debug!("Compact block {} does not match its merkle root, requesting it in full", hash);
+                peer.write(Message::GetBlocks(vec![hash]));
+            }
+        }
+    }
+
+    /// Validate full blocks from a peer and connect them, along with any orphans they complete
+    fn receive_blocks(&self, peer: &peer::Handle, blocks: Vec<Block>) {
+        trace!("Received {} blocks from peer {}", blocks.len(), peer.addr());
+        // println!("total block in chain {}",self.blkchain.lock().unwrap().get_num());
+
+        let mut new_block_hashes: Vec<H256> = Vec::new();
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        peer.mark_known(&blocks.iter().map(|b| b.hash()).collect::<Vec<_>>());
+        // checks that don't need the chain are done first, so the write lock is only held to connect
+        let blocks: Vec<Block> = blocks
+            .into_iter()
+            .filter(|block| {
+                if !self.take_request(peer, &block.hash()) {
+                    return false;
+                }
+                if block.hash() > block.head.difficulty {
+                    self.server.misbehaving(peer.id(), Misbehavior::InvalidProofOfWork);
+                    return false;
+                }
+                if block.transactions().iter().any(|tx| !verify(&tx.tx, &tx.public_key, &tx.signature)) {
+                    self.server.misbehaving(peer.id(), Misbehavior::InvalidSignature);
+                    return false;
+                }
+                true
+            })
+            .collect();
+        if blocks.is_empty() {
+            return;
+        }
+        // transactions of connected blocks, removed from the mempool before the chain is released
+        let mut confirmed: Vec<SignedTrans> = Vec::new();
+        let mut blockchain = locks::write_chain(&self.blkchain);
+        for block in blocks {
+            if !blockchain.key_val.contains_key(&block.hash()){
+                let mut parent = block.head.block_parent;
+                if blockchain.key_val.contains_key(&parent) {
+                    match blockchain.check_block(&block) {
+                        Ok(()) => {}
+                        // the peer's clock may just be ahead, it can send the block again later
+                        Err(e @ BlockError::FromFuture(_)) => {
+                            info!("Ignoring block {} from peer {}: {}", block.hash(), peer.addr(), e);
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Invalid block {} from peer {}: {}", block.hash(), peer.addr(), e);
+                            self.server.misbehaving(peer.id(), Misbehavior::InvalidBlock);
+                            continue;
+                        }
+                    }
+                    if !blockchain.verify_blk(&block){
+                        continue;
+                    }
+                    confirmed.extend(block.body.data.iter().cloned());
+                    blockchain.insert(&block);
+                    // time stamps come from the peer's clock, which may be ahead of ours
+                    blockchain.prop_time += start.saturating_sub(block.head.time_stamp);
+                    debug!("Block {} propagated in {}ms", block.hash(), start.saturating_sub(block.head.time_stamp));
+                    new_block_hashes.push(block.hash());
+                    let mut flag = 1;
+                    while flag!=0{
+                        flag = 0;
+                        let buf = std::mem::replace(&mut blockchain.orphan_buf, Vec::new());
+                        let mut new_buf = Vec::new();
+                        for orp in buf{
+                            parent = orp.head.block_parent;
+                            if blockchain.key_val.contains_key(&parent){
+                                flag = 1;
+                                // blockchain.update_state(&orp);
+                                if let Err(e) = blockchain.check_block(&orp) {
+                                    warn!("Invalid orphan block {}: {}", orp.hash(), e);
+                                    continue;
+                                }
+                                if !blockchain.verify_blk(&orp){
+                                    continue;
+                                }
+                                confirmed.extend(orp.body.data.iter().cloned());
+                                blockchain.insert(&orp);
+                                blockchain.prop_time += start.saturating_sub(orp.head.time_stamp);
+                                debug!("Block {} propagated in {}ms", orp.hash(), start.saturating_sub(orp.head.time_stamp));
+                                new_block_hashes.push(orp.hash());
+                            }
+                            else{
+                                new_buf.push(orp);
+                            }
+                        }
+                        blockchain.orphan_buf = new_buf;
+                    }
+                }
+                else{
+                    blockchain.orphan_buf.push(block);
+                    //peer.write(Message::GetBlocks(vec![parent]));
+                }
+            }
+        }
+        if !confirmed.is_empty() {
+            let mut pool = locks::lock_pool(&self.mem_pool);
+            for tx in &confirmed {
+                pool.remove(tx);
+            }
+        }
+        debug!("Total blocks in chain {}", blockchain.get_num());
+        drop(blockchain);
+        if new_block_hashes.len() > 0 {
+            self.server.broadcast(Message::NewBlockHashes(new_block_hashes));
         }
     }
 
     fn worker_loop(&self) {
+        let mut last_retry = Instant::now();
         loop {
-            let msg = self.msg_chan.recv().unwrap();
-            let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            if last_retry.elapsed() >= RETRY_INTERVAL {
+                self.retry_requests();
+                last_retry = Instant::now();
+            }
+            let msg = match self.msg_chan.recv_timeout(RETRY_INTERVAL) {
+                Ok(msg) => msg,
+                Err(channel::RecvTimeoutError::Timeout) => continue,
+                Err(channel::RecvTimeoutError::Disconnected) => return,
+            };
+            let (command, msg, peer) = msg;
+            let msg: Message = match message::decode(&msg) {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!("Undecodable message from peer {}: {}", peer.addr(), e);
+                    self.server.misbehaving(peer.id(), Misbehavior::DecodeError);
+                    continue;
+                }
+            };
+            // the queue lane was picked by the command, so it has to be the message's own
+            if msg.command() != command {
+                warn!("Peer {} sent a {} message labeled {}", peer.addr(), msg.command(), command);
+                self.server.misbehaving(peer.id(), Misbehavior::MislabeledMessage);
+                continue;
+            }
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -72,34 +297,41 @@ impl Context {
                 }
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
+                    self.server.pong_received(peer.id(), nonce);
                 }
                 Message::NewBlockHashes(block_hashes) => {
                     println!("Received a NewBlockHash message");
+                    peer.mark_known(&block_hashes);
                     // println!("total block in chain {}",self.blkchain.lock().unwrap().get_num());
 
                     let mut new_block_hashes: Vec<H256> = Vec::new();
-                    let mut blockchain = self.blkchain.lock().unwrap();
+                    let blockchain = locks::read_chain(&self.blkchain);
                     //let hash = block_hashes.get(0).unwrap();
                     for hash in block_hashes{
                         if !blockchain.key_val.contains_key(&hash){
                             new_block_hashes.push(hash);
                         }
                     }
+                    drop(blockchain);
+                    let new_block_hashes = self.request(&peer, Kind::Block, new_block_hashes);
                     if new_block_hashes.len() > 0 {
-                        peer.write(Message::GetBlocks(new_block_hashes));
+                        peer.write(Message::GetCompactBlocks(new_block_hashes));
                     }
                 }
                 Message::NewTransactionHashes(tx_hash) => {
                     println!("NewTransactionHashes");
+                    peer.mark_known(&tx_hash);
                     // println!("total block in chain {}",self.blkchain.lock().unwrap().get_num());
 
                     let mut new_tx_hashes:Vec<H256> = Vec::new();
-                    let mut mem_pool = self.mem_pool.lock().unwrap();
+                    let mut mem_pool = locks::lock_pool(&self.mem_pool);
                     for hash in tx_hash{
                         if !mem_pool.pool.contains_key(&hash){
                             new_tx_hashes.push(hash);
                         }
                     }
+                    drop(mem_pool);
+                    let new_tx_hashes = self.request(&peer, Kind::Transaction, new_tx_hashes);
                     if !new_tx_hashes.is_empty(){
                         peer.write(Message::GetTransactions(new_tx_hashes));
                     }
@@ -109,13 +341,15 @@ impl Context {
                     // println!("total block in chain {}",self.blkchain.lock().unwrap().get_num());
 
                     let mut new_blocks: Vec<Block> = Vec::new();
-                    let mut blockchain = self.blkchain.lock().unwrap();
+                    let blockchain = locks::read_chain(&self.blkchain);
                     for hash in block_hashes{
                         if blockchain.key_val.contains_key(&hash){
                             new_blocks.push(blockchain.key_val.get(&hash).unwrap().clone());
                         }
                     }
+                    drop(blockchain);
                     if new_blocks.len() > 0 {
+                        peer.mark_known(&new_blocks.iter().map(|b| b.hash()).collect::<Vec<_>>());
                         peer.write(Message::Blocks(new_blocks));
                     }
                 }
@@ -124,7 +358,7 @@ impl Context {
                     // println!("total block in chain {}",self.blkchain.lock().unwrap().get_num());
 
                     let mut new_tx:Vec<SignedTrans> = Vec::new();
-                    let mem_pool = self.mem_pool.lock().unwrap();
+                    let mem_pool = locks::lock_pool(&self.mem_pool);
                     // let pool = mem_pool.get_pool().clone();
                     for hash in tx_hash{
                         if mem_pool.pool.contains_key(&hash){
@@ -133,115 +367,125 @@ impl Context {
                         }
                     }
                     if ! new_tx.is_empty(){
+                        peer.mark_known(&new_tx.iter().map(|t| t.hash()).collect::<Vec<_>>());
                         peer.write(Message::Transactions(new_tx));
                     }
                 }
-                Message::Blocks(blocks) => {
-                    let mut blockchain = self.blkchain.lock().unwrap();
-                    println!("Received blocks");
-                    // println!("total block in chain {}",self.blkchain.lock().unwrap().get_num());
-
-                    let mut new_block_hashes: Vec<H256> = Vec::new();
-                    let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
-                    for block in blocks {
-                        if !blockchain.key_val.contains_key(&block.hash()){
-                            let mut parent = block.head.block_parent;
-                            if blockchain.key_val.contains_key(&parent) {
-                                let encoded: Vec<u8> = bincode::serialize(&block).unwrap();
-                                let size = encoded.len();
-                                let mut pool = self.mem_pool.lock().unwrap();
-                                if !blockchain.verify_blk(&block){
-                                    continue;
-                                }
-                                let signed_tx = block.body.data.clone();
-                                for tx in signed_tx{
-                                    pool.remove(&tx);
-                                }
-                                blockchain.insert(&block);
-                                blockchain.prop_time+= start - block.head.time_stamp;
-                                // println!("block size = {:?}", size);
-                                println!("Time elapsed in receiving one block is: {:?}ms",start - block.head.time_stamp);
-                                new_block_hashes.push(block.hash());
-                                let mut flag = 1;
-                                while flag!=0{
-                                    flag = 0;
-                                    let buf = blockchain.orphan_buf.clone();
-                                    let mut new_buf = Vec::new();
-                                    for orp in buf{
-                                        parent = orp.head.block_parent;
-                                        if blockchain.key_val.contains_key(&parent){
-                                            flag = 1;
-                                            // blockchain.update_state(&orp);
-                                            if !blockchain.verify_blk(&block){
-                                                continue;
-                                            }
-                                            let signed_tx = orp.body.data.clone();
-                                            for tx in signed_tx{
-                                                pool.remove(&tx);
-                                            }
-                                            blockchain.insert(&orp);
-                                            // drop(signed_tx);
-                                            blockchain.prop_time+=start-orp.head.time_stamp;
-                                            println!("Time elapsed in receiving one block is: {:?}ms", start - orp.head.time_stamp);
-                                            new_block_hashes.push(orp.hash());
-                                        }
-                                        else{
-                                            new_buf.push(orp);
-                                        }
-                                    }
-                                    blockchain.orphan_buf = new_buf;
-                                }
-                            }
-                            else{
-                                blockchain.orphan_buf.push(block);
-                                //peer.write(Message::GetBlocks(vec![parent]));
-                            }
-                        }
+                Message::Blocks(blocks) => self.receive_blocks(&peer, blocks),
+                Message::GetCompactBlocks(block_hashes) => {
+                    let blockchain = locks::read_chain(&self.blkchain);
+                    let compact: Vec<CompactBlock> = block_hashes
+                        .iter()
+                        .filter_map(|hash| blockchain.key_val.get(hash))
+                        .map(CompactBlock::new)
+                        .collect();
+                    drop(blockchain);
+                    for block in compact {
+                        peer.mark_known(&[block.hash()]);
+                        peer.write(Message::CompactBlock(block));
+                    }
+                }
+                Message::CompactBlock(compact) => {
+                    let hash = compact.hash();
+                    peer.mark_known(&[hash]);
+                    if !self.expecting(&peer, &hash) {
+                        continue;
                     }
-                    println!("total block in chain {}",blockchain.get_num());
+                    if hash > compact.head.difficulty {
+                        self.server.misbehaving(peer.id(), Misbehavior::InvalidProofOfWork);
+                        continue;
+                    }
+                    let partial = PartialBlock::new(compact, &locks::lock_pool(&self.mem_pool).pool);
+                    self.complete_block(&peer, hash, partial);
+                }
+                Message::GetBlockTransactions(hash, indexes) => {
+                    let blockchain = locks::read_chain(&self.blkchain);
+                    let found: Option<(Vec<u32>, Vec<SignedTrans>)> = blockchain.key_val.get(&hash).map(|block| {
+                        indexes
+                            .into_iter()
+                            .filter_map(|i| block.body.data.get(i as usize).map(|tx| (i, tx.clone())))
+                            .unzip()
+                    });
                     drop(blockchain);
-                    if new_block_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewBlockHashes(new_block_hashes));
+                    if let Some((indexes, txs)) = found {
+                        peer.write(Message::BlockTransactions(hash, indexes, txs));
+                    }
+                }
+                Message::BlockTransactions(hash, indexes, txs) => {
+                    if !self.expecting(&peer, &hash) {
+                        continue;
+                    }
+                    let partial = self.partial.lock().unwrap().remove(&hash);
+                    if let Some(mut partial) = partial {
+                        partial.fill(&indexes, txs);
+                        self.complete_block(&peer, hash, partial);
                     }
                 }
                 Message::Transactions(txes) => {
                     println!("Transaction");
                     // println!("total block in chain {}",self.blkchain.lock().unwrap().get_num());
-                    let mut mem_pool = self.mem_pool.lock().unwrap().clone();
-                    let mut new_tx_hashes = Vec::new();
-                    let mut chain = self.blkchain.lock().unwrap();
-                    // let mut pool = mem_pool.get_pool();
-                    let pool = mem_pool.pool.clone();
+                    peer.mark_known(&txes.iter().map(|t| t.hash()).collect::<Vec<_>>());
+                    // signatures are checked before taking any lock
+                    let mut valid: Vec<SignedTrans> = Vec::new();
                     for tx in txes{
-                        if !pool.contains_key(&tx.hash()){
-                            let pub_key = tx.get_public_key();
-                            let trans = tx.get_tx();
-                            let sig = tx.get_sig();
-                            let is_verified = verify(&trans, &pub_key, &sig);
-                            let is_over_spend = trans.output_val() > trans.input_val();
-                            if is_verified && !(is_over_spend) {
-                                let buf = tx.clone();
-                                self.mem_pool.lock().unwrap().pool.insert(tx.hash(), buf);
-                                new_tx_hashes.push(tx.hash());
-                                chain.update_state(&tx.tx);
-                            }
+                        if !self.take_request(&peer, &tx.hash()) {
+                            continue;
+                        }
+                        let trans = tx.get_tx();
+                        if !verify(&trans, &tx.get_public_key(), &tx.get_sig()) {
+                            self.server.misbehaving(peer.id(), Misbehavior::InvalidSignature);
+                            continue;
+                        }
+                        if trans.output_val() <= trans.input_val() {
+                            valid.push(tx);
+                        }
+                    }
+                    let mut new_tx_hashes: Vec<H256> = Vec::new();
+                    let (mut chain, mut mem_pool) = locks::lock_both(&self.blkchain, &self.mem_pool);
+                    for tx in valid {
+                        if !mem_pool.pool.contains_key(&tx.hash()) {
+                            mem_pool.add(&tx);
+                            chain.update_state(&tx.tx);
+                            new_tx_hashes.push(tx.hash());
                         }
                     }
+                    drop(mem_pool);
                     drop(chain);
                     if !new_tx_hashes.is_empty() {
                         self.server.broadcast(Message::NewTransactionHashes(new_tx_hashes));
                     }
-
-
+                }
+                Message::GetAddr => {
+                    let addrs = self.addrman.lock().unwrap().addresses(MAX_ADDR_PER_MESSAGE);
+                    if !addrs.is_empty() {
+                        peer.write(Message::Addr(addrs));
+                    }
+                }
+                Message::Addr(addrs) => {
+                    let mut addrman = self.addrman.lock().unwrap();
+                    let new_addrs: Vec<_> = addrs
+                        .into_iter()
+                        .take(MAX_ADDR_PER_MESSAGE)
+                        .filter(|a| addrman.add(a))
+                        .collect();
+                    drop(addrman);
+                    // relay small announcements of addresses we had not heard of, like a node advertising itself
+                    if !new_addrs.is_empty() && new_addrs.len() <= ADDR_RELAY_LIMIT {
+                        debug!("Learned {} new peer addresses from {}", new_addrs.len(), peer.addr());
+                        self.server.broadcast(Message::Addr(new_addrs));
+                    }
+                }
+                Message::Version(_) | Message::VerAck | Message::Auth(_) => {
+                    // the handshake is done by the server before messages reach the workers
+                    debug!("Ignoring handshake message after handshake completed");
                 }
                 Message::Address(add)=>{
                     println!("new address");
-                    let mut blockchain = self.blkchain.lock().unwrap();
+                    let mut blockchain = locks::write_chain(&self.blkchain);
                     let mut newadd = vec![];
                     for address in add{
-                        if !blockchain.address_list.contains(&address){
+                        if blockchain.allocate(address){
                             newadd.push(address);
-                            blockchain.address_list.push(address);
                         }
                     }
                     // println!("{:?}", blockchain.address_list);
@@ -249,6 +493,7 @@ impl Context {
                         for address in blockchain.address_list.clone(){
                             newadd.push(address);
                         }
+                        drop(blockchain);
                         self.server.broadcast(Message::Address(newadd));
                     }
                 }
@@ -0,0 +1,167 @@
+//! Consensus parameters, which differ between the networks a node can join.
+
+use crate::block::{Block, Content, Header};
+use crate::crypto::hash::H256;
+use crate::crypto::merkle::MerkleTree;
+use crate::network::handshake::Network;
+use hex_literal::hex;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The rules blocks of a chain must follow. All nodes of a network have to agree on them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Proof of work target of the genesis block, no block may have an easier one
+    pub genesis_difficulty: H256,
+    /// Time stamp of the genesis block, in milliseconds
+    pub genesis_time: u64,
+    /// Value the coinbase of a block may pay to its miner
+    pub block_reward: u8,
+    /// Maximum number of transactions in a block, not counting the coinbase
+    pub max_block_size: usize,
+    /// Time between blocks the difficulty is adjusted towards
+    pub target_block_time: Duration,
+    /// Number of blocks between difficulty adjustments, 0 keeps the genesis difficulty
+    pub retarget_window: usize,
+    /// Value each address is given when it becomes known
+    pub initial_allocation: u8,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: Network::Mainnet,
+            genesis_difficulty: hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            genesis_time: 0,
+            block_reward: 10,
+            max_block_size: 3,
+            target_block_time: Duration::from_secs(10),
+            retarget_window: 100,
+            initial_allocation: 10,
+        }
+    }
+
+    /// Like mainnet, with easier blocks that are retargeted more often
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: Network::Testnet,
+            genesis_difficulty: hex!("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            target_block_time: Duration::from_secs(5),
+            retarget_window: 20,
+            ..ChainParams::mainnet()
+        }
+    }
+
+    /// For local testing, every hash is a valid block and the difficulty never changes
+    pub fn regtest() -> Self {
+        ChainParams {
+            network: Network::Regtest,
+            genesis_difficulty: [0xff; 32].into(),
+            retarget_window: 0,
+            ..ChainParams::mainnet()
+        }
+    }
+
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Mainnet => ChainParams::mainnet(),
+            Network::Testnet => ChainParams::testnet(),
+            Network::Regtest => ChainParams::regtest(),
+        }
+    }
+
+    pub fn genesis(&self) -> Block {
+        let data = Vec::new();
+        Block {
+            head: Header {
+                block_parent: [0; 32].into(),
+                nonce: 0,
+                difficulty: self.genesis_difficulty,
+                mkl_root: MerkleTree::new(&data).root(),
+                time_stamp: self.genesis_time,
+            },
+            body: Content { data },
+            index: 0,
+        }
+    }
+
+    /// The target following `difficulty` when the last retarget window took `elapsed` milliseconds.
+    /// The target changes by at most a factor of 4, and never becomes easier than the genesis one.
+    pub fn retarget(&self, difficulty: &H256, elapsed: u64) -> H256 {
+        let expected = self.target_block_time.as_millis() as u64 * (self.retarget_window as u64 - 1);
+        let elapsed = elapsed.max(expected / 4).min(expected * 4);
+        let target = scale(difficulty, elapsed, expected);
+        if target > self.genesis_difficulty {
+            self.genesis_difficulty
+        } else {
+            target
+        }
+    }
+}
+
+/// `value * num / den`, saturating at the largest hash
+fn scale(value: &H256, num: u64, den: u64) -> H256 {
+    let bytes: [u8; 32] = value.into();
+    // little endian limbs, with one more for the overflow of the multiplication
+    let mut limbs = [0u64; 5];
+    for (i, chunk) in bytes.rchunks(8).enumerate() {
+        let mut limb = [0; 8];
+        limb.copy_from_slice(chunk);
+        limbs[i] = u64::from_be_bytes(limb);
+    }
+    let mut carry = 0u128;
+    for limb in limbs.iter_mut() {
+        let product = *limb as u128 * num as u128 + carry;
+        *limb = product as u64;
+        carry = product >> 64;
+    }
+    let mut remainder = 0u128;
+    for limb in limbs.iter_mut().rev() {
+        let dividend = remainder << 64 | *limb as u128;
+        *limb = (dividend / den as u128) as u64;
+        remainder = dividend % den as u128;
+    }
+    if limbs[4] != 0 {
+        return [0xff; 32].into();
+    }
+    let mut bytes = [0u8; 32];
+    for (i, chunk) in bytes.rchunks_mut(8).enumerate() {
+        chunk.copy_from_slice(&limbs[i].to_be_bytes());
+    }
+    bytes.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retarget_bounds() {
+        let params = ChainParams::testnet();
+        let start: H256 = hex!("00000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        let expected = 5_000 * 19;
+        assert_eq!(params.retarget(&start, expected), start);
+        // blocks came twice as fast, so the target halves
+        assert_eq!(
+            params.retarget(&start, expected / 2),
+            hex!("000007ffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into()
+        );
+        // at most 4 times harder
+        assert_eq!(
+            params.retarget(&start, 0),
+            hex!("000003ffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into()
+        );
+        // slower blocks make it easier, but not easier than the genesis block
+        assert_eq!(
+            params.retarget(&start, expected * 2),
+            hex!("00001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe").into()
+        );
+        assert_eq!(
+            params.retarget(&start, expected * 100),
+            hex!("00003ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffc").into()
+        );
+        let genesis = params.genesis_difficulty;
+        assert_eq!(params.retarget(&genesis, expected * 2), genesis);
+    }
+}
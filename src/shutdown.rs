@@ -0,0 +1,97 @@
+use crate::miner::Handle as MinerHandle;
+use crate::network::addrman::AddrManager;
+use crate::network::server::Handle as ServerHandle;
+use crate::txgen::Handle as TxGenHandle;
+use crossbeam::channel::{self, Receiver, Sender};
+use log::{error, info};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Exit status after a clean shutdown
+pub const EXIT_OK: i32 = 0;
+/// Exit status when state could not be saved on shutdown
+pub const EXIT_SAVE_FAILED: i32 = 1;
+/// Exit status when a second request interrupts the shutdown
+pub const EXIT_FORCED: i32 = 130;
+
+/// Waits for a shutdown request and stops the node's subsystems in order.
+pub struct Context {
+    requests: Receiver<&'static str>,
+}
+
+/// Requests a shutdown, e.g. from a signal handler or the API.
+#[derive(Clone)]
+pub struct Handle {
+    requests: Sender<&'static str>,
+    requested: Arc<AtomicBool>,
+}
+
+/// The subsystems that are stopped on shutdown.
+pub struct Subsystems {
+    pub txgen: TxGenHandle,
+    pub txgen_thread: thread::JoinHandle<()>,
+    pub miner: MinerHandle,
+    pub miner_thread: thread::JoinHandle<()>,
+    pub server: ServerHandle,
+    pub workers: Vec<thread::JoinHandle<()>>,
+    pub addrman: Arc<Mutex<AddrManager>>,
+}
+
+pub fn new() -> (Context, Handle) {
+    let (sender, receiver) = channel::bounded(1);
+    let ctx = Context { requests: receiver };
+    let handle = Handle {
+        requests: sender,
+        requested: Arc::new(AtomicBool::new(false)),
+    };
+    (ctx, handle)
+}
+
+impl Handle {
+    /// Ask the node to shut down, returns false if a shutdown was already requested
+    pub fn request(&self, reason: &'static str) -> bool {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let _ = self.requests.try_send(reason);
+        true
+    }
+}
+
+impl Context {
+    /// Block until a shutdown is requested, then stop generating transactions and mining, disconnect the network, let the
+    /// workers drain their queue and save the peer addresses. Returns the process exit status.
+    ///
+    /// The generator and miner are waited for before the network goes down, as they may be
+    /// inserting a block or spending from the wallet and broadcast the result.
+    pub fn wait(self, subsystems: Subsystems) -> i32 {
+        let reason = self.requests.recv().unwrap_or("all handles dropped");
+        info!("Shutting down: {}", reason);
+
+        subsystems.txgen.exit();
+        subsystems.miner.exit();
+        if subsystems.txgen_thread.join().is_err() {
+            error!("The transaction generator panicked during shutdown");
+        }
+        if subsystems.miner_thread.join().is_err() {
+            error!("The miner panicked during shutdown");
+        }
+        info!("Stopped the transaction generator and miner");
+        subsystems.server.shutdown();
+        let num_workers = subsystems.workers.len();
+        for worker in subsystems.workers {
+            if worker.join().is_err() {
+                error!("A worker thread panicked during shutdown");
+            }
+        }
+        info!("Stopped {} worker threads", num_workers);
+
+        if let Err(e) = subsystems.addrman.lock().unwrap().save() {
+            error!("Error saving peer addresses: {}", e);
+            return EXIT_SAVE_FAILED;
+        }
+        info!("Shutdown complete");
+        EXIT_OK
+    }
+}
@@ -68,6 +68,14 @@ impl SignedTrans{
     pub fn get_tx(&self) -> Transaction{self.clone().tx}
     pub fn get_sig(&self) -> Vec<u8>{self.clone().signature}
     pub fn get_public_key(&self) -> Vec<u8>{self.clone().public_key}
+
+    /// Whether this is shaped like a coinbase, see [`coin_base`]
+    pub fn is_coinbase(&self) -> bool {
+        self.signature.is_empty()
+            && self.public_key.is_empty()
+            && self.tx.tx_in.len() == 1
+            && self.tx.tx_in[0].val == 0
+    }
 }
 
 impl Hashable for SignedTrans {
@@ -200,17 +208,26 @@ pub fn verify(tx: &Transaction, public_key: &[u8], signature: &[u8]) -> bool{
     return result.is_ok();
 }
 
-pub fn coin_base(address: &H160) -> Transaction{
-    use hex_literal::hex;
+/// The hash an input refers to the output at `index` of a transaction by. The first output is
+/// referred to by the transaction's hash, the others by a hash of it and their index.
+pub fn output_id(tx_hash: &H256, index: usize) -> H256 {
+    if index == 0 {
+        return *tx_hash;
+    }
+    let encoded: Vec<u8> = bincode::serialize(&(tx_hash, index as u32)).unwrap();
+    digest::digest(&digest::SHA256, &encoded).into()
+}
 
-    let hash = (hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
+/// The unsigned transaction paying a block's reward, the first one in the block. Its input refers
+/// to the parent block, so the coinbases of different blocks have different hashes.
+pub fn coin_base(address: &H160, reward: u8, parent: &H256) -> SignedTrans{
     let input = Input{
         val: 0,
-        previous_hash: hash,
+        previous_hash: *parent,
     };
 
     let output = Output{
-        val: 10,
+        val: reward,
         address: address.clone(),
     };
     let t = Transaction{
@@ -218,7 +235,11 @@ pub fn coin_base(address: &H160) -> Transaction{
         tx_in: vec![input],
         tx_out: vec![output],
     };
-    t
+    SignedTrans{
+        tx: t,
+        signature: Vec::new(),
+        public_key: Vec::new(),
+    }
 }
 
 
@@ -0,0 +1,206 @@
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{Hashable, H160, H256};
+use crate::locks;
+use crate::mempool::Mempool;
+use crate::network::message::Message;
+use crate::network::server::Handle as ServerHandle;
+use crate::transaction::{sign, Input, Output, SignedTrans, Transaction};
+
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use log::{debug, info};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the wallet address is announced while generating, so peers that connected since
+/// accept the transactions it signs
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+/// Slowest rate transactions are generated at, one every ~17 minutes
+pub const MIN_RATE: f64 = 0.001;
+
+enum ControlSignal {
+    Start(f64), // transactions per second
+    Stop,
+    Exit,
+}
+
+enum OperatingState {
+    Stopped,
+    Run(f64),
+    ShutDown,
+}
+
+/// Generates test traffic: transactions spending the outputs of the node's wallet to known addresses.
+pub struct Context {
+    control_chan: Receiver<ControlSignal>,
+    operating_state: OperatingState,
+    server: ServerHandle,
+    blkchain: Arc<RwLock<Blockchain>>,
+    mem_pool: Arc<Mutex<Mempool>>,
+    key: Ed25519KeyPair,
+    address: H160,
+    last_announce: Option<Instant>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    control_chan: Sender<ControlSignal>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    blkchain: &Arc<RwLock<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    key: Ed25519KeyPair,
+) -> (Context, Handle) {
+    let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let address = H160::hash(key.public_key().as_ref());
+
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        operating_state: OperatingState::Stopped,
+        server: server.clone(),
+        blkchain: Arc::clone(blkchain),
+        mem_pool: Arc::clone(mempool),
+        key,
+        address,
+        last_announce: None,
+    };
+
+    let handle = Handle {
+        control_chan: signal_chan_sender,
+    };
+
+    (ctx, handle)
+}
+
+impl Handle {
+    /// Generate `rate` transactions per second
+    pub fn start(&self, rate: f64) {
+        let _ = self.control_chan.send(ControlSignal::Start(rate));
+    }
+
+    pub fn stop(&self) {
+        let _ = self.control_chan.send(ControlSignal::Stop);
+    }
+
+    /// Stop the generator thread, does nothing if it has already exited
+    pub fn exit(&self) {
+        let _ = self.control_chan.send(ControlSignal::Exit);
+    }
+}
+
+impl Context {
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
+            .name("txgen".to_string())
+            .spawn(move || {
+                self.generator_loop();
+            })
+            .unwrap();
+        info!("Transaction generator initialized into stopped mode");
+        handle
+    }
+
+    fn handle_control_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Start(rate) => {
+                info!("Transaction generator starting at {} transactions per second", rate);
+                self.operating_state = OperatingState::Run(rate);
+            }
+            ControlSignal::Stop => {
+                info!("Transaction generator stopped");
+                self.operating_state = OperatingState::Stopped;
+            }
+            ControlSignal::Exit => {
+                info!("Transaction generator shutting down");
+                self.operating_state = OperatingState::ShutDown;
+            }
+        }
+    }
+
+    fn generator_loop(&mut self) {
+        loop {
+            let rate = match self.operating_state {
+                OperatingState::Stopped => {
+                    match self.control_chan.recv() {
+                        Ok(signal) => self.handle_control_signal(signal),
+                        Err(_) => return,
+                    }
+                    continue;
+                }
+                OperatingState::ShutDown => return,
+                OperatingState::Run(rate) => rate,
+            };
+
+            if self.last_announce.map_or(true, |t| t.elapsed() >= ANNOUNCE_INTERVAL) {
+                self.server.broadcast(Message::Address(vec![self.address]));
+                self.last_announce = Some(Instant::now());
+            }
+            match self.spend() {
+                Some(hash) => self.server.broadcast(Message::NewTransactionHashes(vec![hash])),
+                None => debug!("No spendable outputs in the wallet"),
+            }
+
+            // wait for the next transaction, unless told otherwise
+            match self.control_chan.recv_timeout(Duration::from_secs_f64(1.0 / rate)) {
+                Ok(signal) => self.handle_control_signal(signal),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Send part of an output of the wallet to a random known address, with the change going
+    /// back to the wallet. Returns the hash of the transaction added to the mempool.
+    fn spend(&self) -> Option<H256> {
+        let mut rng = rand::thread_rng();
+        // the output is picked and spent under one lock, so no other spend can take it meanwhile
+        let (mut chain, mut pool) = locks::lock_both(&self.blkchain, &self.mem_pool);
+        let (previous_hash, output) = chain
+            .current_state
+            .map
+            .iter()
+            .find(|(_, output)| output.address == self.address && output.val > 0)
+            .map(|(hash, output)| (*hash, output.clone()))?;
+        let others: Vec<H160> = chain
+            .address_list
+            .iter()
+            .cloned()
+            .filter(|address| *address != self.address)
+            .collect();
+        let recipient = others.choose(&mut rng).cloned().unwrap_or(self.address);
+
+        // the upper bound does not fit in a u8 when the output holds 255
+        let val = rng.gen_range(1u16, output.val as u16 + 1) as u8;
+        let mut tx_out = vec![Output {
+            val,
+            address: recipient,
+        }];
+        if val < output.val {
+            tx_out.push(Output {
+                val: output.val - val,
+                address: self.address,
+            });
+        }
+        let tx = Transaction {
+            id: rng.gen(),
+            tx_in: vec![Input {
+                val: output.val,
+                previous_hash,
+            }],
+            tx_out,
+        };
+        let signed = SignedTrans {
+            signature: sign(&tx, &self.key),
+            public_key: self.key.public_key().as_ref().to_vec(),
+            tx,
+        };
+        chain.update_state(&signed.tx);
+        pool.add(&signed);
+        Some(signed.hash())
+    }
+}